@@ -14,9 +14,7 @@ fn main() {
 
     let addr = "127.0.0.1:4150".parse().unwrap();
 
-    let mut messages: Vec<String> = Vec::new();
-    messages.push("First message".into());
-    messages.push("Second message".into());
+    let messages = vec!["First message", "Second message"];
 
     let res = Producer::connect(&addr, &handle, Config::default())
        .and_then(|conn| {
@@ -16,7 +16,7 @@ fn main() {
 
     let res = Producer::connect(&addr, &handle, Config::default())
        .and_then(|conn| {
-           conn.publish("some_topic".into(), "some_message".into())
+           conn.publish("some_topic".into(), "some_message")
            .and_then(move |response| {
               println!("Response: {:?}", response);
               Ok(())
@@ -2,32 +2,29 @@ extern crate futures;
 extern crate tokio_core;
 extern crate nsqueue;
 
-use futures::{Stream, Future};
+use futures::Future;
 use tokio_core::reactor::Core;
 use nsqueue::config::*;
 use nsqueue::consumer::*;
+use nsqueue::response::Message;
 
 fn main() {
      let mut core = Core::new().unwrap();
      let handle = core.handle();
      let addr = "127.0.0.1:4150".parse().unwrap();
+     let config = Config { max_in_flight: 10, ..Config::default() };
 
      core.run(
-         Consumer::connect(&addr, &handle, Config::default())
+         Consumer::connect(&addr, &handle, config)
          .and_then(|conn| {
             conn.subscribe("some_topic".into(), "some_channel".into())
-            .and_then(move |response| {
-                let ret = response.for_each(move |message| {
-                    if message.message_id == "_heartbeat_" {
-                        conn.nop();
-                    } else {
-                        println!("Response {:?} {:?}", message.message_id, message.message_body);
-                        conn.fin(message.message_id); // Inform NSQ (Message consumed)
-                    }
-                    Ok(())
-                });
-                ret
+            .and_then(move |stream| {
+                // Up to 10 messages at once, finished when the handler succeeds.
+                conn.add_handler(stream, |message: &Message| {
+                    println!("Response {:?} {:?}", message.message_id, message.message_body);
+                    Ok::<_, String>(())
+                }, 10)
             })
          })
      ).unwrap();
-}
\ No newline at end of file
+}
@@ -0,0 +1,90 @@
+//! How long a consumer stops taking messages after handlers failed.
+//!
+//! The strategies are given the number of failures in a row and
+//! `Config::backoff_multiplier`, the consumer caps what they return at
+//! `Config::max_backoff_duration`.
+
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+
+pub trait BackoffStrategy {
+    /// Interval to back off for after `attempt` failures in a row,
+    /// starting at 1.
+    fn calculate(&self, attempt: u32, multiplier: Duration) -> Duration;
+}
+
+/// `multiplier * 2^attempt`, less up to a quarter at random so consumers
+/// failing together do not come back together. The default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExponentialBackoff;
+
+impl BackoffStrategy for ExponentialBackoff {
+    fn calculate(&self, attempt: u32, multiplier: Duration) -> Duration {
+        let interval = exponential(attempt, multiplier);
+        interval - random_up_to(interval / 4)
+    }
+}
+
+/// Anything between 0 and `multiplier * 2^attempt`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FullJitterBackoff;
+
+impl BackoffStrategy for FullJitterBackoff {
+    fn calculate(&self, attempt: u32, multiplier: Duration) -> Duration {
+        random_up_to(exponential(attempt, multiplier))
+    }
+}
+
+/// Always `multiplier`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConstantBackoff;
+
+impl BackoffStrategy for ConstantBackoff {
+    fn calculate(&self, _attempt: u32, multiplier: Duration) -> Duration {
+        multiplier
+    }
+}
+
+pub(crate) fn exponential(attempt: u32, multiplier: Duration) -> Duration {
+    2u32.checked_pow(attempt)
+        .and_then(|factor| multiplier.checked_mul(factor))
+        .unwrap_or(Duration::MAX)
+}
+
+fn random_up_to(max: Duration) -> Duration {
+    let nanos = max.as_nanos().min(u128::from(u64::MAX)) as u64;
+    Duration::from_nanos(thread_rng().gen_range(0..=nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn exponential_doubles_with_some_jitter() {
+        for attempt in 1..8 {
+            let interval = ExponentialBackoff.calculate(attempt, SECOND);
+            let full = SECOND * 2u32.pow(attempt);
+            assert!(interval <= full && interval >= full * 3 / 4, "{:?} at {}", interval, attempt);
+        }
+        // Saturates instead of overflowing.
+        assert!(ExponentialBackoff.calculate(200, SECOND) >= Duration::MAX - Duration::MAX / 4);
+    }
+
+    #[test]
+    fn full_jitter_stays_below_the_exponential() {
+        for attempt in 1..8 {
+            assert!(FullJitterBackoff.calculate(attempt, SECOND) <= SECOND * 2u32.pow(attempt));
+        }
+        assert_eq!(FullJitterBackoff.calculate(3, Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn constant_is_the_multiplier() {
+        assert_eq!(ConstantBackoff.calculate(1, SECOND), SECOND);
+        assert_eq!(ConstantBackoff.calculate(10, SECOND), SECOND);
+    }
+}
@@ -0,0 +1,233 @@
+//! Drives an nsqd connection once the handshake is done.
+//!
+//! nsqd answers PUB, MPUB, DPUB, IDENTIFY, AUTH, SUB and CLS, with a
+//! response or an error frame, in the order they were sent. FIN, REQ,
+//! TOUCH, RDY and NOP are never answered, they are only written. Error
+//! frames about those, like E_FIN_FAILED, go to the message stream along
+//! with the messages.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use codec::{NsqFrame, NsqResponse, NsqResponseBody, CLOSE_WAIT};
+use commands::Command;
+use error::NsqError;
+use transport::NsqTransport;
+
+/// Messages of a subscribed connection, and the error frames about the
+/// commands nsqd does not answer.
+pub type Messages = mpsc::UnboundedReceiver<NsqResponseBody>;
+
+/// Handle to a connection, which closes once every handle is dropped and
+/// the calls made are answered.
+#[derive(Clone)]
+pub struct NsqClient {
+    requests: mpsc::UnboundedSender<Request>,
+    // Taken by the first subscription.
+    messages: Rc<RefCell<Option<Messages>>>,
+}
+
+enum Request {
+    // Written, nsqd does not answer.
+    Send(Command),
+    // Answered by nsqd in order.
+    Call(Command, oneshot::Sender<NsqResponse>),
+    // Not written, gives back the RDY slot of a message left to time out.
+    Release,
+}
+
+// Runs the connection, until nsqd or the clients close it.
+struct Driver<T> {
+    transport: NsqTransport<T>,
+    requests: mpsc::UnboundedReceiver<Request>,
+    // Taken from `requests`, waiting for the transport to take it.
+    pending: Option<Request>,
+    // No client is left, the connection closes once written out and
+    // every call was answered.
+    released: bool,
+    // Callers of the commands nsqd still has to answer, oldest first.
+    waiting: VecDeque<oneshot::Sender<NsqResponse>>,
+    // Gone once nsqd confirmed CLS.
+    messages: Option<mpsc::UnboundedSender<NsqResponseBody>>,
+}
+
+impl NsqClient {
+    /// Spawns what runs `transport`.
+    pub fn spawn<T>(transport: NsqTransport<T>, handle: &Handle) -> NsqClient
+        where T: AsyncRead + AsyncWrite + 'static
+    {
+        let (requests, received) = mpsc::unbounded();
+        let (messages, stream) = mpsc::unbounded();
+        let driver = Driver {
+            transport,
+            requests: received,
+            pending: None,
+            released: false,
+            waiting: VecDeque::new(),
+            messages: Some(messages),
+        };
+        handle.spawn(driver);
+
+        NsqClient { requests, messages: Rc::new(RefCell::new(Some(stream))) }
+    }
+
+    /// Writes a command nsqd does not answer. Only fails when the
+    /// connection is already gone.
+    pub fn send(&self, command: Command) -> Result<(), NsqError> {
+        self.requests.unbounded_send(Request::Send(command)).map_err(|_| closed())
+    }
+
+    /// Resolves to nsqd's response, error frames fail it.
+    pub fn call(&self, command: Command) -> Box<dyn Future<Item = String, Error = NsqError>> {
+        let (answer, response) = oneshot::channel();
+        if self.requests.unbounded_send(Request::Call(command, answer)).is_err() {
+            return Box::new(future::err(closed()));
+        }
+        Box::new(response.then(|response| response.unwrap_or_else(|_| Err(closed()))))
+    }
+
+    /// Lets a message nsqd times out count as no longer in flight, without
+    /// writing anything. Only fails when the connection is already gone.
+    pub fn release(&self) -> Result<(), NsqError> {
+        self.requests.unbounded_send(Request::Release).map_err(|_| closed())
+    }
+
+    /// The message stream, `None` once taken.
+    pub fn messages(&self) -> Option<Messages> {
+        self.messages.borrow_mut().take()
+    }
+}
+
+fn closed() -> NsqError {
+    io::Error::new(io::ErrorKind::NotConnected, "connection closed").into()
+}
+
+impl<T: AsyncRead + AsyncWrite> Driver<T> {
+    // Hands the requests to the transport as long as it takes them.
+    fn write(&mut self) -> io::Result<()> {
+        loop {
+            let request = match self.pending.take() {
+                Some(request) => request,
+                None => match self.requests.poll() {
+                    Ok(Async::Ready(Some(request))) => request,
+                    Ok(Async::Ready(None)) | Err(()) => {
+                        self.released = true;
+                        break;
+                    }
+                    Ok(Async::NotReady) => break,
+                },
+            };
+
+            let (command, answer) = match request {
+                Request::Send(command) => (command, None),
+                Request::Call(command, answer) => (command, Some(answer)),
+                Request::Release => {
+                    self.transport.release();
+                    continue;
+                }
+            };
+            match self.transport.start_send(command)? {
+                AsyncSink::Ready => self.waiting.extend(answer),
+                AsyncSink::NotReady(command) => {
+                    self.pending = Some(match answer {
+                        Some(answer) => Request::Call(command, answer),
+                        None => Request::Send(command),
+                    });
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn received(&mut self, frame: NsqFrame) {
+        match frame {
+            NsqFrame::Response(response) => {
+                // Nothing is delivered after CLS was confirmed.
+                if response == CLOSE_WAIT {
+                    self.messages = None;
+                }
+                self.answer(Ok(response));
+            }
+            NsqFrame::Error(err) => {
+                let unanswered = match err.code() {
+                    Some("E_FIN_FAILED") | Some("E_REQ_FAILED") | Some("E_TOUCH_FAILED") => true,
+                    _ => self.waiting.is_empty(),
+                };
+                if unanswered {
+                    self.deliver(Err(err));
+                } else {
+                    self.answer(Err(err));
+                }
+            }
+            NsqFrame::Message(message) => self.deliver(Ok(message)),
+        }
+    }
+
+    fn answer(&mut self, response: NsqResponse) {
+        if let Some(answer) = self.waiting.pop_front() {
+            // The caller may not wait anymore.
+            let _ = answer.send(response);
+        }
+    }
+
+    fn deliver(&mut self, message: NsqResponseBody) {
+        if let Some(ref messages) = self.messages {
+            let _ = messages.unbounded_send(message);
+        }
+    }
+
+    // Whoever still waits learns why the connection ended.
+    fn fail(&mut self, err: io::Error) {
+        let failed = || NsqError::from(io::Error::new(err.kind(), err.to_string()));
+        for answer in self.waiting.drain(..) {
+            let _ = answer.send(Err(failed()));
+        }
+        if let Some(messages) = self.messages.take() {
+            let _ = messages.unbounded_send(Err(failed()));
+        }
+    }
+
+    fn poll_connection(&mut self) -> Poll<(), io::Error> {
+        self.write()?;
+        let flushed = self.transport.poll_complete()?.is_ready();
+
+        loop {
+            match self.transport.poll()? {
+                Async::Ready(Some(frame)) => self.received(frame),
+                Async::Ready(None) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by nsqd"));
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        if self.released && self.pending.is_none() && self.waiting.is_empty() && flushed {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Future for Driver<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.poll_connection() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => Ok(Async::Ready(())),
+            Err(err) => {
+                self.fail(err);
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
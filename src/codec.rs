@@ -1,190 +1,324 @@
 use std::io;
-use std::io::Cursor;
-use std::iter::Iterator;
 
-use bytes::{Buf, BufMut, BytesMut};
-use tokio_io::codec::{Encoder, Decoder};
-use tokio_proto::streaming::pipeline::Frame;
-use tokio_proto::streaming::{Body, Message};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+use tokio_codec::{Encoder, Decoder};
 use std::str;
 
-use protocol::RequestMessage;
-use response::Message as TypeMessage;
+use error::NsqError;
+use commands::Command;
+use response::{Message as TypeMessage, MessageId};
 
 // Header: Size(4-Byte) + FrameType(4-Byte)
-const HEADER_LENGTH: usize = 8;
+const SIZE_LENGTH: usize = 4;
+const FRAME_TYPE_LENGTH: usize = 4;
+const HEADER_LENGTH: usize = SIZE_LENGTH + FRAME_TYPE_LENGTH;
+
+// Message: Timestamp(8-Byte) + Attempts(2-Byte) + MessageID(16-Byte)
+const MESSAGE_HEADER_LENGTH: usize = 26;
 
 // Frame Types
 const FRAME_TYPE_RESPONSE: i32 = 0x00;
 const FRAME_TYPE_ERROR: i32 = 0x01;
 const FRAME_TYPE_MESSAGE: i32 = 0x02;
 
-const HEARTBEAT: &'static str = "_heartbeat_";
+pub const HEARTBEAT: &str = "_heartbeat_";
+pub const CLOSE_WAIT: &str = "CLOSE_WAIT";
 
-#[derive(Clone)]
-pub struct ClientTypeMap<T> {
-   pub inner: T,
-}
+/// A response frame, or the error frame nsqd sent in its place.
+pub type NsqResponse = Result<String, NsqError>;
+/// A message frame, or an error frame received while streaming messages.
+pub type NsqResponseBody = Result<TypeMessage, NsqError>;
 
-pub type NsqMessage = Message<RequestMessage, Body<RequestMessage, io::Error>>;
-pub type NsqResponseMessage = Message<String, Body<TypeMessage, io::Error>>;
+/// A frame as sent by nsqd.
+#[derive(Debug)]
+pub enum NsqFrame {
+    // Heartbeats included.
+    Response(String),
+    Error(NsqError),
+    Message(TypeMessage),
+}
 
 /// NSQ codec
-pub struct NsqCodec {
-    pub decoding_head: bool
-}
+///
+/// Heartbeats are decoded as responses, `NsqTransport` answers them.
+pub struct NsqCodec;
 
 impl Decoder for NsqCodec {
-    type Item = Frame<String, TypeMessage, io::Error>;
+    type Item = NsqFrame;
     type Error = io::Error;
 
+    /// Decodes exactly one size-prefixed frame from the front of `buf`,
+    /// anything after it stays buffered for the next call.
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
-        let length = buf.len();
-
-        if length < HEADER_LENGTH {
+        if buf.len() < HEADER_LENGTH {
             return Ok(None);
         }
 
-        let mut cursor = Cursor::new(buf.clone());
-        let size: i32 = cursor.get_i32_be();
+        // The size covers the frame type and the data, not itself.
+        let size = BigEndian::read_i32(&buf[..SIZE_LENGTH]);
+        if size < FRAME_TYPE_LENGTH as i32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid frame size"));
+        }
 
-        if length < size as usize {
+        let frame_length = SIZE_LENGTH + size as usize;
+        if buf.len() < frame_length {
+            buf.reserve(frame_length - buf.len());
             return Ok(None);
         }
 
-        let frame_type: i32 = cursor.get_i32_be();
+        let frame_type: i32 = BigEndian::read_i32(&buf[SIZE_LENGTH..HEADER_LENGTH]);
+
+        // remove the serialized frame from the buffer, the data is split
+        // out of it without copying.
+        let mut frame = buf.split_to(frame_length);
+        frame.advance(HEADER_LENGTH);
 
         if frame_type == FRAME_TYPE_RESPONSE {
-            // remove the serialized frame from the buffer.
-            buf.split_to(HEADER_LENGTH + length);
-            match str::from_utf8(&cursor.bytes()) {
-                Ok(s) => {
-                    let decoded_message = s.to_string();
-
-                    // TODO: Implement a proper way to handle the heartbeat
-                    if decoded_message == HEARTBEAT && !self.decoding_head {
-                        Ok(Some(self.heartbeat_message()))
-                    } else if decoded_message == HEARTBEAT {
-                        // toggle streaming
-                        Ok(Some(self.streaming_flag()))
-                    } else {
-                        Ok(Some(Frame::Message {
-                            message: decoded_message,
-                            body: false,
-                        }))
-                    }
-                }
-                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Invalid UTF-8")),
+            match str::from_utf8(&frame) {
+                Ok(s) => Ok(Some(NsqFrame::Response(s.to_string()))),
+                Err(_) => Err(io::Error::other("Invalid UTF-8")),
             }
         } else if frame_type == FRAME_TYPE_ERROR {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid packet received"))
+            // Error frames are handed to the caller instead of failing the
+            // transport, nsqd closes the connection itself when they are fatal.
+            let error = NsqError::from_frame(&String::from_utf8_lossy(&frame));
+            Ok(Some(NsqFrame::Error(error)))
         } else if frame_type == FRAME_TYPE_MESSAGE {
-            if self.decoding_head {
-                // toggle streaming
-                Ok(Some(self.streaming_flag()))
-            } else {
-                let timestamp = cursor.get_i64_be(); // timestamp
-                let _ = cursor.get_u16_be(); // attempts
- 
-                let data = str::from_utf8(&cursor.bytes()).unwrap().to_string();
-                let (id, body) = data.split_at(16);
-
-                let message = TypeMessage{
-                    timestamp: timestamp,
-                    message_id: id.to_string(),
-                    message_body: body.to_string()
-                };
-
-                // remove the serialized frame from the buffer.
-                buf.split_to(HEADER_LENGTH + length);
-
-                Ok(Some(
-                    Frame::Body {
-                        chunk: Some(message),
-                    }
-                ))              
+            if frame.len() < MESSAGE_HEADER_LENGTH {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Message frame too short"));
             }
+
+            let timestamp = BigEndian::read_i64(&frame[..8]);
+            let attempts = BigEndian::read_u16(&frame[8..10]);
+            let mut id = [0u8; 16];
+            id.copy_from_slice(&frame[10..MESSAGE_HEADER_LENGTH]);
+            frame.advance(MESSAGE_HEADER_LENGTH);
+
+            let message = TypeMessage::new(timestamp, attempts, MessageId(id), frame.freeze());
+
+            Ok(Some(NsqFrame::Message(message)))
         } else {
-            Ok(None)
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown frame type {}", frame_type)))
         }
     }
 }
 
-pub type CodecOutputFrame = Frame<RequestMessage, RequestMessage, io::Error>;
 impl Encoder for NsqCodec {
-    type Item = CodecOutputFrame;
+    type Item = Command;
     type Error = io::Error;
 
-    fn encode(&mut self, message: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-        match message {
-            Frame::Message { message, .. } => {
-                if let Some(version) = message.version {
-                    buf.reserve(version.len());
-                    buf.extend(version.as_bytes());
-                }
+    fn encode(&mut self, command: Command, buf: &mut BytesMut) -> io::Result<()> {
+        command.encode(buf);
+        Ok(())
+    }
+}
 
-                if let Some(header) = message.header {
-                    buf.reserve(header.len() + 1);
-                    buf.extend(header.as_bytes());
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+    use std::time::{Duration, UNIX_EPOCH};
 
-                if let Some(body) = message.body {
-                    let mut buf_32 = Vec::with_capacity(body.len());
-                    let body_len = body.len() as u32;
-                    buf_32.put_u32_be(body_len);
-                    buf_32.put(&body[..]);
-                    buf.extend(buf_32);
-                }
+    fn response_frame(data: &[u8]) -> Vec<u8> {
+        frame(FRAME_TYPE_RESPONSE, data)
+    }
 
-                if let Some(body_messages) = message.body_messages {
-                    let total_bytes = body_messages
-                        .iter()
-                        .map(|message| message.len())
-                        .fold(0, |acc, len| acc + len);
-
-                    let mut buf_32 = Vec::with_capacity(total_bytes);
-                    // [4-byte body size]
-                    let body_len = total_bytes as u32;
-                    buf_32.put_u32_be(body_len);
-                    // [4-byte num messages]
-                    let messages_len = body_messages.len() as u32;
-                    buf_32.put_u32_be(messages_len);
-                    // [ 4-byte message #1 size ][ N-byte binary data ] ...
-                    for message in &body_messages {
-                        let message_len = message.len() as u32;
-                        buf_32.put_u32_be(message_len);
-                        buf_32.put(&message[..]);
-                    }
-                    buf.extend(buf_32);
+    fn message_frame(timestamp: i64, attempts: u16, id: &[u8; 16], body: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.put_i64_be(timestamp);
+        data.put_u16_be(attempts);
+        data.extend_from_slice(id);
+        data.extend_from_slice(body);
+        frame(FRAME_TYPE_MESSAGE, &data)
+    }
+
+    fn frame(frame_type: i32, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.put_i32_be((FRAME_TYPE_LENGTH + data.len()) as i32);
+        frame.put_i32_be(frame_type);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn message_stream(count: usize) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut wire = Vec::new();
+        let mut bodies = Vec::new();
+        for i in 0..count {
+            // Bodies of varying size, including empty and non UTF-8 ones.
+            let body: Vec<u8> = (0..(i * 37) % 300).map(|b| (b * 7 + i) as u8).collect();
+            let id = format!("{:016}", i);
+            let mut raw_id = [0u8; 16];
+            raw_id.copy_from_slice(id.as_bytes());
+            wire.extend(message_frame(i as i64, i as u16, &raw_id, &body));
+            bodies.push(body);
+        }
+        (wire, bodies)
+    }
+
+    /// Feeds `chunks` one after another, decoding everything available
+    /// after each one.
+    fn decode_chunks<'a, I>(codec: &mut NsqCodec, chunks: I) -> Vec<NsqFrame>
+        where I: IntoIterator<Item = &'a [u8]>
+    {
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            buf.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert!(buf.is_empty(), "{} bytes left undecoded", buf.len());
+        frames
+    }
+
+    fn assert_messages(frames: Vec<NsqFrame>, bodies: &[Vec<u8>]) {
+        assert_eq!(frames.len(), bodies.len());
+        for (i, (frame, body)) in frames.into_iter().zip(bodies).enumerate() {
+            match frame {
+                NsqFrame::Message(message) => {
+                    assert_eq!(&message.message_body[..], &body[..]);
+                    assert_eq!(message.attempts, i as u16);
+                    assert_eq!(message.message_id.as_bytes(), format!("{:016}", i).as_bytes());
+                    assert_eq!(message.timestamp, UNIX_EPOCH + Duration::from_nanos(i as u64));
                 }
-                Ok(())
+                _ => panic!("frame {} is not a message", i),
             }
-            Frame::Error { error, .. } => Err(error),
-            Frame::Body { .. } => panic!("Streaming of Requests is not currently supported"),
         }
     }
-}
 
-impl NsqCodec {    
-    fn heartbeat_message(&mut self) -> Frame<String, TypeMessage, io::Error>
-    {
-        let message = TypeMessage{
-            timestamp: 0,
-            message_id: HEARTBEAT.to_string(),
-            message_body: HEARTBEAT.to_string()
-        };
+    // Deterministic xorshift so failures are reproducible.
+    struct Rng(u64);
 
-        Frame::Body {
-            chunk: Some(message),
-        } 
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
     }
 
-    fn streaming_flag(&mut self) -> Frame<String, TypeMessage, io::Error>
-    {
-        self.decoding_head = false;
-        Frame::Message {
-            message: "".into(),
-            body: true,
+    #[test]
+    fn decodes_back_to_back_frames_from_one_read() {
+        let (wire, bodies) = message_stream(50);
+        let frames = decode_chunks(&mut NsqCodec, vec![&wire[..]]);
+        assert_messages(frames, &bodies);
+    }
+
+    #[test]
+    fn decodes_frames_fed_byte_by_byte() {
+        let (wire, bodies) = message_stream(20);
+        let frames = decode_chunks(&mut NsqCodec, wire.chunks(1));
+        assert_messages(frames, &bodies);
+    }
+
+    #[test]
+    fn decodes_frames_split_at_random_points() {
+        let (wire, bodies) = message_stream(100);
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..50 {
+            let mut chunks = Vec::new();
+            let mut rest = &wire[..];
+            while !rest.is_empty() {
+                let at = 1 + rng.below(rest.len().min(600));
+                let (chunk, tail) = rest.split_at(at);
+                chunks.push(chunk);
+                rest = tail;
+            }
+
+            let frames = decode_chunks(&mut NsqCodec, chunks);
+            assert_messages(frames, &bodies);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn waits_for_the_rest_of_a_partial_frame() {
+        let wire = response_frame(b"OK");
+        let mut codec = NsqCodec;
+        let mut buf = BytesMut::from(&wire[..wire.len() - 1]);
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), wire.len() - 1);
+
+        buf.extend_from_slice(&wire[wire.len() - 1..]);
+        match codec.decode(&mut buf).unwrap() {
+            Some(NsqFrame::Response(message)) => assert_eq!(message, "OK"),
+            _ => panic!("expected an OK response"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn leaves_following_frames_buffered() {
+        let mut wire = response_frame(b"OK");
+        let second = response_frame(b"CLOSE_WAIT");
+        wire.extend_from_slice(&second);
+
+        let mut codec = NsqCodec;
+        let mut buf = BytesMut::from(&wire[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert_eq!(&buf[..], &second[..]);
+    }
+
+    #[test]
+    fn decodes_heartbeats_as_responses() {
+        let mut wire = response_frame(HEARTBEAT.as_bytes());
+        wire.extend(message_frame(0, 1, b"0000000000000000", b"body"));
+
+        let frames = decode_chunks(&mut NsqCodec, vec![&wire[..]]);
+
+        assert_eq!(frames.len(), 2);
+        match frames[0] {
+            NsqFrame::Response(ref heartbeat) => assert_eq!(heartbeat, HEARTBEAT),
+            _ => panic!("expected a heartbeat"),
+        }
+        match frames[1] {
+            NsqFrame::Message(ref message) => assert_eq!(&message.message_body[..], b"body"),
+            _ => panic!("expected a message"),
+        }
+    }
+
+    #[test]
+    fn decodes_error_frames_without_failing() {
+        let wire = frame(FRAME_TYPE_ERROR, b"E_BAD_TOPIC PUB topic name \"!\" is not valid");
+        let mut buf = BytesMut::from(&wire[..]);
+        match NsqCodec.decode(&mut buf).unwrap() {
+            Some(NsqFrame::Error(NsqError::BadTopic(text))) => {
+                assert_eq!(text, "PUB topic name \"!\" is not valid");
+            }
+            _ => panic!("expected an E_BAD_TOPIC response"),
+        }
+
+        let mut wire = frame(FRAME_TYPE_ERROR, b"E_FIN_FAILED FIN 0000000000000000 failed ID not in flight");
+        wire.extend(message_frame(0, 0, b"0000000000000000", b""));
+        let frames = decode_chunks(&mut NsqCodec, vec![&wire[..]]);
+        match frames[0] {
+            NsqFrame::Error(ref err) => {
+                assert_eq!(err.code(), Some("E_FIN_FAILED"));
+                assert!(!err.is_fatal());
+            }
+            _ => panic!("expected an E_FIN_FAILED response"),
+        }
+        match frames[1] {
+            NsqFrame::Message(_) => {}
+            _ => panic!("expected the stream to carry on"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_frame_sizes() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 2, 0, 0, 0, 0][..]);
+        assert!(NsqCodec.decode(&mut buf).is_err());
+
+        let mut wire = frame(FRAME_TYPE_MESSAGE, b"too short");
+        let mut buf = BytesMut::from(&wire[..]);
+        assert!(NsqCodec.decode(&mut buf).is_err());
+
+        wire = frame(0x7f, b"");
+        let mut buf = BytesMut::from(&wire[..]);
+        assert!(NsqCodec.decode(&mut buf).is_err());
+    }
+}
@@ -1,15 +1,192 @@
-pub mod commands {
-    pub const VERSION_2: &'static str = "  V2";
-    
-    pub const PUB: &'static str = "PUB";
-    pub const MPUB: &'static str = "MPUB";
-    pub const DPUB: &'static str = "DPUB";
+use bytes::{BufMut, Bytes, BytesMut};
 
-    pub const SUB: &'static str = "SUB";
+use response::MessageId;
 
-    pub const RDY: &'static str = "RDY";
-    pub const FIN: &'static str = "FIN";
+/// Magic sent once, before any command, to select the V2 protocol.
+pub const VERSION_2: &[u8] = b"  V2";
 
-    pub const NOP: &'static str = "NOP";
-    pub const IDENTIFY: &'static str = "IDENTIFY";
-}
\ No newline at end of file
+/// The commands of the NSQ V2 protocol.
+///
+/// Durations are sent in milliseconds, bodies are sent as-is behind a
+/// 4-byte size.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Command {
+    /// JSON encoded metadata about the client.
+    Identify(Bytes),
+    /// Secret used to authenticate the connection.
+    Auth(Bytes),
+    Sub { topic: String, channel: String },
+    Pub { topic: String, body: Bytes },
+    Mpub { topic: String, bodies: Vec<Bytes> },
+    Dpub { topic: String, defer_ms: u64, body: Bytes },
+    Rdy(u64),
+    Fin(MessageId),
+    Req { id: MessageId, timeout_ms: u64 },
+    Touch(MessageId),
+    Cls,
+    Nop,
+}
+
+impl Command {
+    /// Writes the command in its wire format.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match *self {
+            Command::Identify(ref data) => {
+                write_line(buf, &[b"IDENTIFY"]);
+                write_body(buf, data);
+            }
+            Command::Auth(ref secret) => {
+                write_line(buf, &[b"AUTH"]);
+                write_body(buf, secret);
+            }
+            Command::Sub { ref topic, ref channel } => {
+                write_line(buf, &[b"SUB", topic.as_bytes(), channel.as_bytes()]);
+            }
+            Command::Pub { ref topic, ref body } => {
+                write_line(buf, &[b"PUB", topic.as_bytes()]);
+                write_body(buf, body);
+            }
+            Command::Mpub { ref topic, ref bodies } => {
+                write_line(buf, &[b"MPUB", topic.as_bytes()]);
+
+                // The body size covers the message count and every
+                // message with its own size prefix.
+                let body_len = 4 + bodies.iter().map(|body| 4 + body.len()).sum::<usize>();
+                buf.reserve(4 + body_len);
+                buf.put_u32_be(body_len as u32);
+                buf.put_u32_be(bodies.len() as u32);
+                for body in bodies {
+                    buf.put_u32_be(body.len() as u32);
+                    buf.put_slice(body);
+                }
+            }
+            Command::Dpub { ref topic, defer_ms, ref body } => {
+                write_line(buf, &[b"DPUB", topic.as_bytes(), defer_ms.to_string().as_bytes()]);
+                write_body(buf, body);
+            }
+            Command::Rdy(count) => {
+                write_line(buf, &[b"RDY", count.to_string().as_bytes()]);
+            }
+            Command::Fin(ref id) => {
+                write_line(buf, &[b"FIN", id.as_bytes()]);
+            }
+            Command::Req { ref id, timeout_ms } => {
+                write_line(buf, &[b"REQ", id.as_bytes(), timeout_ms.to_string().as_bytes()]);
+            }
+            Command::Touch(ref id) => {
+                write_line(buf, &[b"TOUCH", id.as_bytes()]);
+            }
+            Command::Cls => write_line(buf, &[b"CLS"]),
+            Command::Nop => write_line(buf, &[b"NOP"]),
+        }
+    }
+}
+
+// Writes the space separated parameters followed by a newline.
+fn write_line(buf: &mut BytesMut, params: &[&[u8]]) {
+    let len = params.iter().map(|param| param.len() + 1).sum();
+    buf.reserve(len);
+    for (i, param) in params.iter().enumerate() {
+        if i > 0 {
+            buf.put_u8(b' ');
+        }
+        buf.put_slice(param);
+    }
+    buf.put_u8(b'\n');
+}
+
+// Writes a 4-byte size followed by the data.
+fn write_body(buf: &mut BytesMut, data: &[u8]) {
+    buf.reserve(4 + data.len());
+    buf.put_u32_be(data.len() as u32);
+    buf.put_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: MessageId = MessageId(*b"0a1b2c3d4e5f6789");
+
+    fn encoded(command: Command) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        command.encode(&mut buf);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn identify() {
+        let command = Command::Identify(Bytes::from_static(b"{\"client_id\":\"test\"}"));
+        assert_eq!(encoded(command), b"IDENTIFY\n\x00\x00\x00\x14{\"client_id\":\"test\"}".to_vec());
+    }
+
+    #[test]
+    fn auth() {
+        let command = Command::Auth(Bytes::from_static(b"s3cr3t"));
+        assert_eq!(encoded(command), b"AUTH\n\x00\x00\x00\x06s3cr3t".to_vec());
+    }
+
+    #[test]
+    fn sub() {
+        let command = Command::Sub { topic: "events".into(), channel: "archive#ephemeral".into() };
+        assert_eq!(encoded(command), b"SUB events archive#ephemeral\n".to_vec());
+    }
+
+    #[test]
+    fn publish() {
+        let command = Command::Pub { topic: "events".into(), body: Bytes::from_static(b"\x00\xffhello") };
+        assert_eq!(encoded(command), b"PUB events\n\x00\x00\x00\x07\x00\xffhello".to_vec());
+    }
+
+    #[test]
+    fn mpub() {
+        let command = Command::Mpub {
+            topic: "events".into(),
+            bodies: vec![Bytes::from_static(b"one"), Bytes::from_static(b""), Bytes::from_static(b"three")],
+        };
+        let expected = b"MPUB events\n\
+            \x00\x00\x00\x18\
+            \x00\x00\x00\x03\
+            \x00\x00\x00\x03one\
+            \x00\x00\x00\x00\
+            \x00\x00\x00\x05three";
+        assert_eq!(encoded(command), expected.to_vec());
+    }
+
+    #[test]
+    fn dpub() {
+        let command = Command::Dpub { topic: "events".into(), defer_ms: 1500, body: Bytes::from_static(b"later") };
+        assert_eq!(encoded(command), b"DPUB events 1500\n\x00\x00\x00\x05later".to_vec());
+    }
+
+    #[test]
+    fn rdy() {
+        assert_eq!(encoded(Command::Rdy(2500)), b"RDY 2500\n".to_vec());
+    }
+
+    #[test]
+    fn fin() {
+        assert_eq!(encoded(Command::Fin(ID)), b"FIN 0a1b2c3d4e5f6789\n".to_vec());
+    }
+
+    #[test]
+    fn req() {
+        let command = Command::Req { id: ID, timeout_ms: 60000 };
+        assert_eq!(encoded(command), b"REQ 0a1b2c3d4e5f6789 60000\n".to_vec());
+    }
+
+    #[test]
+    fn touch() {
+        assert_eq!(encoded(Command::Touch(ID)), b"TOUCH 0a1b2c3d4e5f6789\n".to_vec());
+    }
+
+    #[test]
+    fn cls() {
+        assert_eq!(encoded(Command::Cls), b"CLS\n".to_vec());
+    }
+
+    #[test]
+    fn nop() {
+        assert_eq!(encoded(Command::Nop), b"NOP\n".to_vec());
+    }
+}
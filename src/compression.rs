@@ -0,0 +1,52 @@
+//! What the snappy and deflate streams share.
+
+use std::error::Error;
+use std::io;
+
+// Data that can not be compressed or decompressed.
+pub fn corrupt<E: Into<Box<dyn Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::cmp;
+    use std::io::{self, Read};
+
+    /// Yields one byte at a time and blocks in between.
+    pub struct Trickle<'a>(&'a [u8], bool);
+
+    impl<'a> Trickle<'a> {
+        pub fn new(data: &'a [u8]) -> Trickle<'a> {
+            Trickle(data, false)
+        }
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 && !self.0.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = cmp::min(1, self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    /// Reads everything, a few bytes at a time, trying again whenever the
+    /// stream blocks.
+    pub fn read_all<R: Read>(mut stream: R) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return out,
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => panic!("{}", err),
+            }
+        }
+    }
+}
@@ -1,9 +1,8 @@
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     // Identifiers sent to nsqd representing this client
     pub client_id: Option<String>,
-    pub short_id: Option<String>,
-    pub long_id: Option<String>,
     pub hostname: Option<String>,
     pub user_agent: String,
 
@@ -14,31 +13,101 @@ pub struct Config {
 
     pub feature_negotiation: bool,
 
-    // Duration of time between heartbeats.
+    // Milliseconds between heartbeats (-1 to disable, 0 for nsqd's default).
     pub heartbeat_interval: i64,
 
-    // Timeout used by nsqd before flushing buffered writes (set to 0 to disable).
+    // Milliseconds nsqd waits for a message to be finished before
+    // requeueing it (0 for nsqd's default).
     pub message_timeout: u32,
 
-    // Size of the buffer (in bytes) used by nsqd for buffering writes to this connection
-    pub output_buffer_size: u64,
-    pub output_buffer_timeout: u32,
+    // Size of the buffer (in bytes) used by nsqd for buffering writes to
+    // this connection, and milliseconds before it is flushed anyway
+    // (-1 to disable, 0 for nsqd's default).
+    pub output_buffer_size: i64,
+    pub output_buffer_timeout: i64,
 
     // Integer percentage to sample the channel (requires nsqd 0.2.25+)
     pub sample_rate: u16,
 
     // tls_v1 - Bool enable TLS negotiation
     pub tls_v1: bool,
+
+    // Milliseconds a message whose handler failed is requeued for, per
+    // attempt, up to max_requeue_delay.
+    pub requeue_delay: u64,
+    pub max_requeue_delay: u64,
+
+    // Messages delivered more often than this are given up on and finished
+    // (0 for no limit).
+    pub max_attempts: u16,
+
+    // Topic on the same nsqd messages given up on are published to, with
+    // `{topic}` replaced by the topic subscribed to.
+    pub dead_letter_topic: Option<String>,
+
+    // Messages in flight at most, over all nsqd connections of a consumer.
+    pub max_in_flight: u64,
+
+    // With more connections than max_in_flight, milliseconds between
+    // rotations of which ones get RDY, and after which a connection without
+    // messages makes way for another.
+    pub rdy_redistribute_interval: u64,
+    pub low_rdy_idle_timeout: u64,
+
+    // Milliseconds passed to the backoff strategy, and the longest a
+    // consumer backs off for (0 disables backoff).
+    pub backoff_multiplier: u64,
+    pub max_backoff_duration: u64,
+
+    // Touch messages still being handled at half the msg_timeout, for at
+    // most max_msg_timeout.
+    pub auto_touch: bool,
+
+    // Milliseconds between queries of nsqlookupd, and the fraction of it
+    // taken off at random so consumers do not all query at once.
+    pub lookupd_poll_interval: u64,
+    pub lookupd_poll_jitter: f64,
+
+    // Milliseconds an nsqlookupd has to answer a query, one that takes
+    // longer counts as not answering.
+    pub lookupd_timeout: u64,
+
+    // Milliseconds an nsqd has to accept the connection and answer the
+    // handshake, and for consumers again to answer SUB. One that takes
+    // longer counts as not reachable, a reconnect attempt as failed.
+    pub dial_timeout: u64,
+
+    // Milliseconds before connecting again to an nsqd the connection to
+    // was lost, doubled for every failed attempt up to max_reconnect_delay
+    // (0 to not reconnect).
+    pub reconnect_delay: u64,
+    pub max_reconnect_delay: u64,
+
+    // Publishes a producer waits to send until it reconnected, the ones
+    // beyond fail right away.
+    pub publish_queue_size: usize,
+
+    // What happens to a delivery dropped without being acknowledged.
+    pub on_drop: DropAction,
+
+    // Milliseconds `Consumer::stop` gives the messages in flight to be
+    // finished or requeued, before requeueing them itself.
+    pub drain_timeout: u64,
+
+    // Settings for the TLS upgrade, used when nsqd agrees to tls_v1.
+    #[serde(skip)]
+    pub tls: TlsConfig,
+
+    // Secret sent with AUTH when nsqd requires it, never part of IDENTIFY.
+    #[serde(skip)]
+    pub auth_secret: Option<String>,
 }
 use hostname::get_hostname;
 
-#[allow(dead_code)]
-impl Config {
-    pub fn default() -> Config {
+impl Default for Config {
+    fn default() -> Config {
         Config {
             client_id: get_hostname(),
-            short_id: get_hostname(),
-            long_id: get_hostname(),
             user_agent: String::from("github.com/wisespace-io/nsqueue"),
             hostname: get_hostname(),
             deflate: false,
@@ -51,9 +120,33 @@ impl Config {
             output_buffer_timeout: 250,
             sample_rate: 0,
             tls_v1: false,
+            requeue_delay: 90_000,
+            max_requeue_delay: 900_000,
+            max_attempts: 0,
+            dead_letter_topic: None,
+            max_in_flight: 1,
+            rdy_redistribute_interval: 5_000,
+            low_rdy_idle_timeout: 10_000,
+            backoff_multiplier: 1_000,
+            max_backoff_duration: 120_000,
+            auto_touch: false,
+            lookupd_poll_interval: 60_000,
+            lookupd_poll_jitter: 0.3,
+            lookupd_timeout: 5_000,
+            dial_timeout: 5_000,
+            reconnect_delay: 1_000,
+            max_reconnect_delay: 60_000,
+            publish_queue_size: 0,
+            on_drop: DropAction::Requeue,
+            drain_timeout: 30_000,
+            tls: TlsConfig::default(),
+            auth_secret: None,
         }
     }
+}
 
+#[allow(dead_code)]
+impl Config {
     pub fn client_id(mut self, client_id: String) -> Self {
         self.client_id = Some(client_id);
         self
@@ -69,8 +162,62 @@ impl Config {
         self
     }
 
+    /// Requests deflate at `level`, which nsqd lowers to its max_deflate_level.
+    pub fn deflate(mut self, level: u16) -> Self {
+        self.deflate = true;
+        self.deflate_level = level;
+        self
+    }
+
     pub fn snappy(mut self, snappy: bool) -> Self {
         self.snappy = snappy;
         self
-    }    
+    }
+
+    /// Requests tls_v1 and sets how the upgraded connection is verified.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls_v1 = true;
+        self.tls = tls;
+        self
+    }
+
+    /// Publishes messages failing `max_attempts` times to `topic`, e.g.
+    /// `{topic}.dlq`, instead of dropping them.
+    pub fn dead_letter_topic(mut self, max_attempts: u16, topic: String) -> Self {
+        self.max_attempts = max_attempts;
+        self.dead_letter_topic = Some(topic);
+        self
+    }
+
+    pub fn auth_secret(mut self, secret: String) -> Self {
+        self.auth_secret = Some(secret);
+        self
+    }
+}
+
+/// Acknowledgement sent for a `Delivery` dropped without one.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DropAction {
+    /// Requeue it like a failed handler would.
+    Requeue,
+    Finish,
+    /// Leave it to nsqd, which delivers it again after msg_timeout.
+    Ignore,
+}
+
+/// How the connection is verified once upgraded to TLS.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    // PEM encoded certificates trusted in addition to the system roots.
+    pub root_certificates: Vec<Vec<u8>>,
+
+    // PEM encoded certificate and PKCS #8 key presented to nsqd, for mutual TLS.
+    pub client_certificate: Option<(Vec<u8>, Vec<u8>)>,
+
+    // Name checked against the certificate of nsqd, defaults to the address
+    // connected to.
+    pub server_name: Option<String>,
+
+    // Accept any certificate. Only meant for development.
+    pub insecure_skip_verify: bool,
 }
@@ -1,104 +1,906 @@
-use futures::{Future, future};
+use futures::{future, Future, IntoFuture, Stream};
+use futures::future::Shared;
+use futures::sync::{mpsc, oneshot};
 
-use tokio_service::Service;
-use tokio_core::reactor::Handle;
-use tokio_proto::{TcpClient};
-use tokio_proto::util::client_proxy::ClientProxy;
-use tokio_proto::streaming::{Message};
+use rand::{thread_rng, Rng};
+use tokio_core::reactor::{Handle, Interval, Timeout};
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::io;
+use std::mem;
 use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
+use backoff::BackoffStrategy;
 use config::Config;
-use response::{ResponseStream};
-use codec::{NsqMessage, NsqResponseMessage, ClientTypeMap};
-use protocol::{NsqProtocol, RequestMessage};
+use dead_letter::DeadLetter;
+use error::NsqError;
+use handler::Handler;
+use event::{ConnectionEvent, EventHook};
+use lookup::Lookupd;
+use response::{self, Delivery, ResponseStream, MessageId, NegotiatedFeatures, AuthIdentity};
+use client::{Messages, NsqClient};
+use commands::Command;
+use producer::Producer;
+use protocol::{self, Closed, Session};
+use rdy::RdyControl;
 
+// What nsqd uses when IDENTIFY left msg_timeout to it.
+const DEFAULT_MSG_TIMEOUT: u64 = 60_000;
+
+type GiveUpHook = Rc<dyn Fn(&response::Message)>;
+
+// Where the connections send their messages, read by the `ResponseStream`.
+type Deliveries = mpsc::UnboundedSender<Result<Delivery, NsqError>>;
+
+// Connects to nsqd instances nsqlookupd listed, resolving once each of
+// them is connected to or failed.
+type Connecting = Box<dyn Future<Item = (), Error = NsqError>>;
+
+// The connections of a consumer, by the nsqd they go to.
+pub(crate) type Connections = Rc<RefCell<BTreeMap<SocketAddr, Connection>>>;
+
+// One of the nsqd connections of a consumer.
+#[derive(Clone)]
+pub(crate) struct Connection {
+    inner: NsqClient,
+    session: Session,
+    // Connection to the same nsqd, publishing to the dead-letter topic.
+    dead_letters: Option<Producer>,
+    // Stops forwarding the messages of the connection once dropped.
+    forwarding: Rc<RefCell<Option<oneshot::Sender<()>>>>,
+    closed: Shared<Closed>,
+}
+
+/// Consumes from one or more nsqd connections, which share
+/// `Config::max_in_flight` and deliver into a single `ResponseStream`.
 #[derive(Clone)]
 pub struct Consumer {
-    inner: ClientTypeMap<ClientProxy<NsqMessage, NsqResponseMessage, io::Error>>,
+    connections: Connections,
+    events: EventHook,
+    // What the handshake with the nsqd given to `connect` established.
+    session: Session,
+    config: Config,
+    handle: Handle,
+    rdy: RdyControl,
+    give_up: Rc<RefCell<Option<GiveUpHook>>>,
+    // Topic and channel subscribed to.
+    subscription: Rc<RefCell<Option<(String, String)>>>,
+    deliveries: Rc<RefCell<Option<Deliveries>>>,
+    // Messages delivered and not acknowledged yet, by the nsqd instances
+    // they came from. Ids are only unique per nsqd.
+    unacked: Rc<RefCell<HashMap<MessageId, Vec<SocketAddr>>>>,
+    // Lost connections being reconnected to, and nsqd instances found by
+    // nsqlookupd being connected to.
+    reconnecting: Rc<RefCell<BTreeSet<SocketAddr>>>,
+    connecting: Rc<RefCell<BTreeSet<SocketAddr>>>,
+    // Told once no message is unacknowledged, while stopping.
+    drained: Rc<RefCell<Option<oneshot::Sender<()>>>>,
+    stopped: Rc<Cell<bool>>,
+}
+
+// What runs in the background refers to the consumer through this, the
+// connections close once the consumer and its stream are dropped.
+#[derive(Clone)]
+struct WeakConsumer {
+    connections: Weak<RefCell<BTreeMap<SocketAddr, Connection>>>,
+    events: EventHook,
+    session: Session,
+    config: Config,
+    handle: Handle,
+    rdy: RdyControl,
+    give_up: Rc<RefCell<Option<GiveUpHook>>>,
+    subscription: Rc<RefCell<Option<(String, String)>>>,
+    deliveries: Rc<RefCell<Option<Deliveries>>>,
+    unacked: Rc<RefCell<HashMap<MessageId, Vec<SocketAddr>>>>,
+    reconnecting: Rc<RefCell<BTreeSet<SocketAddr>>>,
+    connecting: Rc<RefCell<BTreeSet<SocketAddr>>>,
+    drained: Rc<RefCell<Option<oneshot::Sender<()>>>>,
+    stopped: Rc<Cell<bool>>,
+}
+
+impl WeakConsumer {
+    fn upgrade(&self) -> Option<Consumer> {
+        Some(Consumer {
+            connections: self.connections.upgrade()?,
+            events: self.events.clone(),
+            session: self.session.clone(),
+            config: self.config.clone(),
+            handle: self.handle.clone(),
+            rdy: self.rdy.clone(),
+            give_up: self.give_up.clone(),
+            subscription: self.subscription.clone(),
+            deliveries: self.deliveries.clone(),
+            unacked: self.unacked.clone(),
+            reconnecting: self.reconnecting.clone(),
+            connecting: self.connecting.clone(),
+            drained: self.drained.clone(),
+            stopped: self.stopped.clone(),
+        })
+    }
 }
 
 impl Consumer {
-    /// Establish a connection and send protocol version.
-    pub fn connect(addr: &SocketAddr, handle: &Handle, config: Config) -> Box<Future<Item = Consumer, Error = io::Error>> {
-        let protocol = NsqProtocol::new(config);
-        let ret = TcpClient::new(protocol)
-            .connect(addr, handle)
-            .map(|client_proxy| {
-                let type_map = ClientTypeMap { inner: client_proxy };
-                Consumer { inner: type_map }
-            });
+    /// Establish a connection and identify. Resolves once nsqd accepted
+    /// the IDENTIFY.
+    ///
+    /// With `Config::dead_letter_topic` a second connection is made to
+    /// publish dead letters, a subscribed connection can not wait for PUB
+    /// to be answered.
+    pub fn connect(addr: &SocketAddr, handle: &Handle, config: Config) -> Box<dyn Future<Item = Consumer, Error = NsqError>> {
+        let mut consumer = Consumer::new(handle, config);
+        let ret = consumer.add_connection(*addr).map(move |session| {
+            consumer.session = session;
+            consumer
+        });
 
         Box::new(ret)
-    } 
-
-    #[allow(unused_variables)]
-    pub fn subscribe(&self, topic: String, channel: String) -> Box<Future<Item = ResponseStream, Error = io::Error>> {
-        let mut request = RequestMessage::new();
-        request.create_sub_command(topic, channel);        
-        
-        let service = self.inner.clone();
-        let resp = service.inner.call(Message::WithoutBody(request))
-            .map_err(|e| {e.into()})
-            .and_then(move |resp| {
-                let mut request = RequestMessage::new();
-                request.create_rdy_command();
-                let rdy = service.inner.call(Message::WithoutBody(request))
-                    .map_err(|e| {e.into()});
-                rdy    
+    }
+
+    /// Connect to every nsqd in `addrs` and subscribe them all to `topic`
+    /// and `channel`. They share `Config::max_in_flight`, their messages
+    /// are merged into the returned stream and acknowledged on the
+    /// connection they came from.
+    ///
+    /// Fails when any of them can not be connected to or subscribed.
+    pub fn connect_to_nsqds(addrs: &[SocketAddr], topic: String, channel: String, handle: &Handle, config: Config)
+        -> Box<dyn Future<Item = (Consumer, ResponseStream), Error = NsqError>>
+    {
+        let mut consumer = Consumer::new(handle, config);
+        let connections: Vec<_> = addrs.iter().map(|addr| consumer.add_connection(*addr)).collect();
+        let failed = consumer.clone();
+        let ret = future::join_all(connections)
+            .and_then(move |sessions| {
+                consumer.session = sessions.into_iter().next().unwrap_or_default();
+                consumer.subscribe(topic, channel).map(move |stream| (consumer, stream))
             })
-            .map(move |resp| {                                  
-                match resp {
-                    Message::WithoutBody(str) => {
-                        panic!("Not implemented: {}", str)
-                    },
-                    Message::WithBody(head, body) => {                
-                        ResponseStream { inner: body }
-                    }
+            .or_else(move |err| {
+                // Those connected to before one failed are closed again.
+                let added: Vec<_> = failed.connections.borrow().keys().cloned().collect();
+                for addr in &added {
+                    failed.remove_connection(addr);
                 }
+                Err(err)
             });
 
-        Box::new(resp)
-    } 
-
-    #[allow(unused_variables)]
-    pub fn fin(&self, message_id: String) -> Box<Future<Item = (), Error = io::Error>> {
-        let mut request = RequestMessage::new();
-        request.create_fin_command(message_id);        
-        
-        let service = self.inner.clone();
-        let resp = service.inner.call(Message::WithoutBody(request))
-            .map_err(|e| e.into())
-            .and_then(|resp| future::ok(()));
+        Box::new(ret)
+    }
+
+    /// Subscribe to `topic` on the nsqd instances the nsqlookupd at `urls`
+    /// know of, given as `http://host:port`. They are queried again every
+    /// `Config::lookupd_poll_interval`, connecting to new nsqd instances
+    /// and dropping those no nsqlookupd lists anymore.
+    ///
+    /// Resolves once the first query was answered and the nsqd instances
+    /// found connected to. An nsqlookupd that did not answer within
+    /// `Config::lookupd_timeout`, or an nsqd that could not be connected to
+    /// within `Config::dial_timeout`, is retried on the next query.
+    pub fn connect_to_lookupds<S: AsRef<str>>(urls: &[S], topic: String, channel: String, handle: &Handle, config: Config)
+        -> Box<dyn Future<Item = (Consumer, ResponseStream), Error = NsqError>>
+    {
+        let lookupds = match urls.iter().map(|url| Lookupd::parse(url.as_ref())).collect::<Result<Vec<_>, _>>() {
+            Ok(lookupds) => Rc::new(lookupds),
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        let consumer = Consumer::new(handle, config);
+        let ret = consumer.subscribe(topic, channel)
+            .and_then(move |stream| {
+                consumer.discover(&lookupds)
+                    .and_then(|connecting| connecting)
+                    .map(move |()| {
+                        consumer.poll_lookupds(lookupds);
+                        (consumer, stream)
+                    })
+            });
+
+        Box::new(ret)
+    }
+
+    fn new(handle: &Handle, config: Config) -> Consumer {
+        Consumer {
+            connections: Rc::default(),
+            events: EventHook::default(),
+            session: Session::default(),
+            rdy: RdyControl::new(&config, handle),
+            config,
+            handle: handle.clone(),
+            give_up: Rc::default(),
+            subscription: Rc::default(),
+            deliveries: Rc::default(),
+            unacked: Rc::default(),
+            reconnecting: Rc::default(),
+            connecting: Rc::default(),
+            drained: Rc::default(),
+            stopped: Rc::default(),
+        }
+    }
+
+    fn downgrade(&self) -> WeakConsumer {
+        WeakConsumer {
+            connections: Rc::downgrade(&self.connections),
+            events: self.events.clone(),
+            session: self.session.clone(),
+            config: self.config.clone(),
+            handle: self.handle.clone(),
+            rdy: self.rdy.clone(),
+            give_up: self.give_up.clone(),
+            subscription: self.subscription.clone(),
+            deliveries: self.deliveries.clone(),
+            unacked: self.unacked.clone(),
+            reconnecting: self.reconnecting.clone(),
+            connecting: self.connecting.clone(),
+            drained: self.drained.clone(),
+            stopped: self.stopped.clone(),
+        }
+    }
+
+    // Connects to another nsqd, subscribing when the others are.
+    fn add_connection(&self, addr: SocketAddr) -> Box<dyn Future<Item = Session, Error = NsqError>> {
+        let consumer = self.clone();
+        let config = self.config.clone();
+        let handle = self.handle.clone();
+        let timeout = Duration::from_millis(config.dial_timeout);
+        let ret = protocol::connect(&addr, &self.handle, config.clone(), self.events.clone(), Some(self.rdy.clone()))
+            .and_then(move |(client, session, closed)| {
+                let dead_letters = match config.dead_letter_topic {
+                    Some(_) => Box::new(Producer::connect(&addr, &handle, config.clone()).map(Some)) as Box<dyn Future<Item = _, Error = _>>,
+                    None => Box::new(future::ok(None)),
+                };
+
+                dead_letters.and_then(move |dead_letters| {
+                    // Closed again right away, the consumer stopped meanwhile.
+                    if consumer.stopped.get() {
+                        let err = io::Error::new(io::ErrorKind::NotConnected, "consumer stopped");
+                        return Box::new(future::err(err.into())) as Box<dyn Future<Item = _, Error = _>>;
+                    }
+
+                    let closed = closed.shared();
+                    let connection = Connection {
+                        inner: client,
+                        session: session.clone(),
+                        dead_letters,
+                        forwarding: Rc::default(),
+                        closed: closed.clone(),
+                    };
+                    consumer.connections.borrow_mut().insert(addr, connection.clone());
+
+                    let watching = consumer.downgrade();
+                    let forwarding = connection.forwarding.clone();
+                    handle.spawn(closed.then(move |_| {
+                        if let Some(consumer) = watching.upgrade() {
+                            consumer.connection_lost(addr, &forwarding);
+                        }
+                        Ok(())
+                    }));
+
+                    let subscribed = consumer.deliveries.borrow().is_some();
+                    let subscription = if subscribed {
+                        protocol::within(consumer.sub(addr, connection), timeout, &handle, "nsqd")
+                    } else {
+                        Box::new(future::ok(()))
+                    };
+                    Box::new(subscription.then(move |res| {
+                        if res.is_err() {
+                            consumer.remove_connection(&addr);
+                        }
+                        res.map(move |()| session)
+                    }))
+                })
+            });
+
+        Box::new(ret)
+    }
+
+    // Closes the connection to an nsqd, its unacknowledged messages are
+    // delivered to another consumer after their timeout.
+    fn remove_connection(&self, addr: &SocketAddr) {
+        self.reconnecting.borrow_mut().remove(addr);
+        let connection = match self.connections.borrow_mut().remove(addr) {
+            Some(connection) => connection,
+            None => return,
+        };
+        self.forget(addr);
+
+        // nsqd answers CLS with CLOSE_WAIT, which ends the message stream.
+        // The connection closes once nothing refers to it anymore, right
+        // away when nothing was subscribed to.
+        if self.deliveries.borrow().is_some() {
+            let closed = connection.inner.call(Command::Cls);
+            self.handle.spawn(closed.then(|_| Ok(())));
+        }
+    }
+
+    // Messages in flight on a connection that is gone can not be
+    // acknowledged anymore, nsqd delivers them again.
+    fn forget(&self, addr: &SocketAddr) {
+        self.unacked.borrow_mut().retain(|_, origins| {
+            origins.retain(|origin| origin != addr);
+            !origins.is_empty()
+        });
+        self.check_drained();
+    }
+
+    // Unless the connection was removed on purpose, it is reconnected to.
+    fn connection_lost(&self, addr: SocketAddr, forwarding: &Rc<RefCell<Option<oneshot::Sender<()>>>>) {
+        let current = self.connections.borrow().get(&addr)
+            .is_some_and(|connection| Rc::ptr_eq(&connection.forwarding, forwarding));
+        if !current {
+            return;
+        }
+        self.connections.borrow_mut().remove(&addr);
+        self.forget(&addr);
+        self.events.emit(ConnectionEvent::Disconnected(addr));
+
+        if self.config.reconnect_delay > 0 && !self.stopped.get() {
+            self.reconnect(addr, 0);
+        }
+    }
+
+    // Waits longer after every failed attempt, until the nsqd is back or
+    // not wanted anymore.
+    fn reconnect(&self, addr: SocketAddr, attempt: u32) {
+        self.reconnecting.borrow_mut().insert(addr);
+        let timeout = match Timeout::new(protocol::reconnect_delay(&self.config, attempt), &self.handle) {
+            Ok(timeout) => timeout,
+            Err(_) => return,
+        };
+
+        let consumer = self.downgrade();
+        let reconnected = timeout.then(move |_| {
+            let consumer = match consumer.upgrade() {
+                Some(consumer) if consumer.reconnecting.borrow().contains(&addr) => consumer,
+                _ => return Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _>>,
+            };
+            consumer.events.emit(ConnectionEvent::Reconnecting(addr));
+
+            Box::new(consumer.add_connection(addr).then(move |res| {
+                match res {
+                    // Removed while connecting.
+                    Ok(_) if !consumer.reconnecting.borrow_mut().remove(&addr) => consumer.remove_connection(&addr),
+                    Ok(_) => consumer.events.emit(ConnectionEvent::Reconnected(addr)),
+                    Err(_) => consumer.reconnect(addr, attempt + 1),
+                }
+                Ok(())
+            }))
+        });
+        self.handle.spawn(reconnected);
+    }
+
+    /// Subscribe every connection to `topic` and `channel`. The messages
+    /// of all of them are delivered by the returned stream.
+    pub fn subscribe(&self, topic: String, channel: String) -> Box<dyn Future<Item = ResponseStream, Error = NsqError>> {
+        let (sender, receiver) = mpsc::unbounded();
+        *self.subscription.borrow_mut() = Some((topic, channel));
+        *self.deliveries.borrow_mut() = Some(sender);
+
+        let connections: Vec<_> = self.connections.borrow().iter()
+            .map(|(addr, connection)| (*addr, connection.clone()))
+            .collect();
+        let subscriptions: Vec<_> = connections.into_iter()
+            .map(|(addr, connection)| self.sub(addr, connection))
+            .collect();
+        let connections = self.connections.clone();
+        let resp = future::join_all(subscriptions).map(move |_| ResponseStream { inner: receiver, _connections: connections });
 
         Box::new(resp)
-    }    
-
-    #[allow(unused_variables)]
-    pub fn nop(&self) -> Box<Future<Item = (), Error = io::Error>> {
-        let mut request = RequestMessage::new();
-        request.create_nop_command();        
-        
-        let service = self.inner.clone();
-        let resp = service.inner.call(Message::WithoutBody(request))
-            .map_err(|e| e.into())
-            .and_then(|resp| future::ok(()));
+    }
+
+    fn sub(&self, addr: SocketAddr, connection: Connection) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        let (topic, channel) = self.subscription.borrow().clone().unwrap();
+        let request = Command::Sub { topic, channel };
+
+        // Lets nsqd send messages, the transport puts this connection's
+        // share of max_in_flight in its place.
+        let rdy = Command::Rdy(0);
+
+        let consumer = self.clone();
+        let client = connection.inner.clone();
+        let resp = client.call(request)
+            .and_then(move |_| {
+                client.send(rdy)?;
+                let messages = client.messages()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "connection already subscribed"))?;
+                consumer.forward(addr, &connection, messages);
+                Ok(())
+            });
 
         Box::new(resp)
-    }    
-}
+    }
+
+    // Passes the messages of a connection on to the `ResponseStream`,
+    // until the connection is removed.
+    fn forward(&self, addr: SocketAddr, connection: &Connection, messages: Messages) {
+        let sender = match self.deliveries.borrow().clone() {
+            Some(sender) => sender,
+            None => return,
+        };
+        let (stop, stopped) = oneshot::channel();
+        *connection.forwarding.borrow_mut() = Some(stop);
+
+        let consumer = self.downgrade();
+        let forwarded = messages.for_each(move |message| {
+            let delivery = match message {
+                Ok(message) => {
+                    // Nothing could acknowledge it, nsqd delivers it again.
+                    let consumer = consumer.upgrade().ok_or(())?;
+                    consumer.unacked.borrow_mut().entry(message.message_id).or_default().push(addr);
+                    Ok(Delivery::new(message, addr, consumer))
+                }
+                // The stream goes on with the connection reconnected to.
+                Err(NsqError::IOError(_)) if consumer.config.reconnect_delay > 0 => return Ok(()),
+                Err(err) => Err(err),
+            };
+            sender.unbounded_send(delivery).map_err(|_| ())
+        });
+        self.handle.spawn(forwarded.select2(stopped).then(|_| Ok(())));
+    }
+
+    // Queries the nsqlookupd again after the poll interval, less jitter.
+    fn poll_lookupds(&self, lookupds: Rc<Vec<Lookupd>>) {
+        let interval = self.config.lookupd_poll_interval as f64;
+        let jitter = self.config.lookupd_poll_jitter.clamp(0.0, 1.0);
+        let delay = interval * (1.0 - thread_rng().gen_range(0.0..=jitter));
+
+        let timeout = match Timeout::new(Duration::from_millis(delay as u64), &self.handle) {
+            Ok(timeout) => timeout,
+            Err(_) => return,
+        };
+        let consumer = self.downgrade();
+        let polled = timeout
+            .map_err(NsqError::from)
+            .and_then(move |()| {
+                // Stopped, or dropped along with its stream.
+                let consumer = match consumer.upgrade() {
+                    Some(consumer) if !consumer.stopped.get() => consumer,
+                    _ => return Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _>>,
+                };
+                // The next query does not wait for nsqd instances that are
+                // slow to connect to.
+                Box::new(consumer.discover(&lookupds).map(move |connecting| {
+                    consumer.handle.spawn(connecting.map_err(|_| ()));
+                    consumer.poll_lookupds(lookupds);
+                }))
+            });
+        self.handle.spawn(polled.map_err(|_| ()));
+    }
+
+    // Brings the connections in line with what the nsqlookupd answered.
+    // When none of them did, the connections are left as they are.
+    // Resolves once they answered, to what connects to the nsqd instances
+    // found.
+    fn discover(&self, lookupds: &[Lookupd]) -> Box<dyn Future<Item = Connecting, Error = NsqError>> {
+        let topic = match *self.subscription.borrow() {
+            Some((ref topic, _)) => topic.clone(),
+            None => return Box::new(future::ok(Box::new(future::ok(())) as Connecting)),
+        };
+        let timeout = Duration::from_millis(self.config.lookupd_timeout);
+        let lookups: Vec<_> = lookupds.iter()
+            .map(|lookupd| lookupd.lookup(&topic, timeout, &self.handle).then(Ok::<_, NsqError>))
+            .collect();
+
+        let consumer = self.clone();
+        let ret = future::join_all(lookups).map(move |answers| {
+            let answered: Vec<_> = answers.into_iter().filter_map(Result::ok).collect();
+            if answered.is_empty() {
+                return Box::new(future::ok(())) as Connecting;
+            }
+            let found: BTreeSet<SocketAddr> = answered.into_iter().flatten().collect();
+
+            let mut known: Vec<SocketAddr> = consumer.connections.borrow().keys().cloned().collect();
+            known.extend(consumer.reconnecting.borrow().iter().cloned());
+            for addr in known.iter().filter(|addr| !found.contains(addr)) {
+                consumer.remove_connection(addr);
+            }
+            known.extend(consumer.connecting.borrow().iter().cloned());
+            let added: Vec<_> = found.into_iter()
+                .filter(|addr| !known.contains(addr))
+                .map(|addr| {
+                    consumer.connecting.borrow_mut().insert(addr);
+                    let connecting = consumer.connecting.clone();
+                    consumer.add_connection(addr).then(move |_| {
+                        connecting.borrow_mut().remove(&addr);
+                        Ok(())
+                    })
+                })
+                .collect();
+            Box::new(future::join_all(added).map(|_| ())) as Connecting
+        });
+
+        Box::new(ret)
+    }
 
-impl<T> Service for ClientTypeMap<T>
-    where T: Service<Request = RequestMessage, Response = NsqResponseMessage, Error = io::Error>,
-          T::Future: 'static
-{
-    type Request = RequestMessage;
-    type Response = NsqResponseMessage;
-    type Error = io::Error;
-    type Future = Box<Future<Item = NsqResponseMessage, Error = io::Error>>;
+    /// Finish a message. nsqd only answers a FIN that failed, the
+    /// E_FIN_FAILED error is then yielded by the message stream.
+    ///
+    /// Counts as a success, bringing the consumer closer out of backoff.
+    pub fn fin(&self, message_id: MessageId) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.fin_from(None, message_id)
+    }
+
+    /// Requeue a message, nsqd delivers it again once `delay` passed.
+    /// Like `fin`, only a failed REQ is answered, with E_REQ_FAILED on the
+    /// message stream.
+    ///
+    /// Counts as a failure and puts the consumer in backoff, use
+    /// `requeue_without_backoff` for messages that are merely deferred.
+    pub fn requeue(&self, message_id: MessageId, delay: Duration) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.requeue_from(None, message_id, delay, true)
+    }
 
-    fn call(&self, req: RequestMessage) -> Self::Future {
-        Box::new(self.inner.call(req))
+    /// Requeue a message without it counting as a failure.
+    pub fn requeue_without_backoff(&self, message_id: MessageId, delay: Duration) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.requeue_from(None, message_id, delay, false)
     }
-}
\ No newline at end of file
+
+    /// Reset the timeout of a message still being processed, so nsqd does
+    /// not deliver it again. nsqd does not extend it past max_msg_timeout.
+    pub fn touch(&self, message_id: MessageId) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.touch_from(None, message_id)
+    }
+
+    // The acknowledgements, sent to the nsqd `from` or, when not known,
+    // to one the message is in flight on.
+    pub(crate) fn fin_from(&self, from: Option<SocketAddr>, message_id: MessageId) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        let (addr, connection) = match self.origin(from, message_id) {
+            Ok(origin) => origin,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        self.rdy.succeeded();
+        self.acked(addr, message_id);
+        send(&connection, Command::Fin(message_id))
+    }
+
+    pub(crate) fn requeue_from(&self, from: Option<SocketAddr>, message_id: MessageId, delay: Duration, backoff: bool)
+        -> Box<dyn Future<Item = (), Error = NsqError>>
+    {
+        let (addr, connection) = match self.origin(from, message_id) {
+            Ok(origin) => origin,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        if backoff {
+            self.rdy.failed();
+        }
+        self.acked(addr, message_id);
+        send(&connection, Command::Req { id: message_id, timeout_ms: delay.as_millis() as u64 })
+    }
+
+    pub(crate) fn touch_from(&self, from: Option<SocketAddr>, message_id: MessageId) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        match self.origin(from, message_id) {
+            Ok((_, connection)) => send(&connection, Command::Touch(message_id)),
+            Err(err) => Box::new(future::err(err)),
+        }
+    }
+
+    /// Runs `handler` on the messages of `stream`, up to `concurrency` at
+    /// once. A message is finished when its handler succeeds and requeued
+    /// when it fails, for `Config::requeue_delay` per attempt. With
+    /// `Config::auto_touch` it is touched while the handler runs.
+    ///
+    /// A message delivered past `Config::max_attempts` is given up on
+    /// instead. With a dead-letter topic that happens as soon as its last
+    /// attempt failed, so the error goes along.
+    ///
+    /// Messages beyond `concurrency` wait in the stream, set
+    /// `Config::max_in_flight` at least as high to keep every handler busy.
+    ///
+    /// Resolves when the stream ends, fails with the first fatal error.
+    pub fn add_handler<H>(&self, stream: ResponseStream, handler: H, concurrency: usize) -> Box<dyn Future<Item = (), Error = NsqError>>
+        where H: Handler + 'static,
+              H::Future: 'static
+    {
+        let consumer = self.clone();
+        let handler = Rc::new(handler);
+        let ret = stream
+            .then(Ok::<_, NsqError>)
+            .map(move |message| {
+                match message {
+                    Ok(message) => consumer.process(&*handler, message),
+                    // A failed FIN or REQ, the message is gone already.
+                    Err(ref err) if !err.is_fatal() => Box::new(future::ok(())),
+                    Err(err) => Box::new(future::err(err)),
+                }
+            })
+            .buffer_unordered(cmp::max(concurrency, 1))
+            .for_each(|()| Ok(()));
+
+        Box::new(ret)
+    }
+
+    /// `add_handler` for a closure, handling one message at a time.
+    pub fn handle<F, R>(&self, stream: ResponseStream, handler: F) -> Box<dyn Future<Item = (), Error = NsqError>>
+        where F: Fn(&response::Message) -> R + 'static,
+              R: IntoFuture<Item = ()>,
+              R::Error: fmt::Display,
+              R::Future: 'static
+    {
+        self.add_handler(stream, handler, 1)
+    }
+
+    fn process<H>(&self, handler: &H, delivery: Delivery) -> Box<dyn Future<Item = (), Error = NsqError>>
+        where H: Handler,
+              H::Future: 'static
+    {
+        if self.exhausted(delivery.attempts) {
+            return self.give_up(delivery, None);
+        }
+
+        let consumer = self.clone();
+        let touching = self.touch_while_handled(delivery.origin(), delivery.message_id);
+        Box::new(handler.handle(&delivery).then(move |res| {
+            drop(touching);
+            // FIN and REQ are not waited for, nsqd does not answer them.
+            match res {
+                Ok(()) => drop(delivery.finish()),
+                Err(err) => {
+                    if consumer.config.dead_letter_topic.is_some() && consumer.exhausted(delivery.attempts.saturating_add(1)) {
+                        return consumer.give_up(delivery, Some(err.to_string()));
+                    }
+                    let delay = consumer.requeue_delay(delivery.attempts);
+                    drop(delivery.requeue(delay));
+                }
+            }
+            Box::new(future::ok(()))
+        }))
+    }
+
+    // Whether a message delivered `attempts` times is past max_attempts.
+    fn exhausted(&self, attempts: u16) -> bool {
+        self.config.max_attempts > 0 && attempts > self.config.max_attempts
+    }
+
+    // The message goes to the give-up hook and is finished, so it stops
+    // coming back. With a dead-letter topic it is only finished once
+    // published there, and requeued if that failed.
+    fn give_up(&self, delivery: Delivery, error: Option<String>) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        let hook = self.give_up.borrow().clone();
+        if let Some(hook) = hook {
+            hook(&delivery);
+        }
+
+        let dead_letters = self.connections.borrow().get(&delivery.origin()).and_then(|connection| connection.dead_letters.clone());
+        let subscription = self.subscription.borrow().clone();
+        let (dead_letters, (topic, channel)) = match (dead_letters, subscription) {
+            (Some(dead_letters), Some(subscription)) => (dead_letters, subscription),
+            _ => {
+                drop(delivery.finish());
+                return Box::new(future::ok(()));
+            }
+        };
+
+        let dead_letter_topic = self.config.dead_letter_topic.as_ref().unwrap().replace("{topic}", &topic);
+        let letter = DeadLetter {
+            topic,
+            channel,
+            attempts: delivery.attempts,
+            error,
+            first_seen: delivery.timestamp,
+            body: delivery.message_body.clone(),
+        };
+
+        let delay = self.requeue_delay(delivery.attempts);
+        Box::new(dead_letters.publish(dead_letter_topic, letter.encode()).then(move |res| {
+            if res.is_ok() {
+                drop(delivery.finish());
+            } else {
+                drop(delivery.requeue(delay));
+            }
+            Ok(())
+        }))
+    }
+
+    // Grows with the attempts, so failing messages come back less often.
+    pub(crate) fn requeue_delay(&self, attempts: u16) -> Duration {
+        let delay = self.config.requeue_delay.saturating_mul(u64::from(attempts));
+        Duration::from_millis(cmp::min(delay, self.config.max_requeue_delay))
+    }
+
+    // Touches at half the msg_timeout until the returned sender is dropped,
+    // or until max_msg_timeout passed and touching would not help anymore.
+    fn touch_while_handled(&self, origin: SocketAddr, message_id: MessageId) -> Option<oneshot::Sender<()>> {
+        if !self.config.auto_touch {
+            return None;
+        }
+
+        let features = self.connections.borrow().get(&origin).and_then(|connection| connection.session.features.clone());
+        let (msg_timeout, max_msg_timeout) = match features {
+            Some(ref features) => (features.msg_timeout, features.max_msg_timeout),
+            None => (u64::from(self.config.message_timeout), 0),
+        };
+        let msg_timeout = if msg_timeout > 0 { msg_timeout } else { DEFAULT_MSG_TIMEOUT };
+
+        let interval = match Interval::new(Duration::from_millis(msg_timeout / 2), &self.handle) {
+            Ok(interval) => interval,
+            Err(_) => return None,
+        };
+        let (stop, stopped) = oneshot::channel();
+        let consumer = self.clone();
+        let delivered = Instant::now();
+        let touches = interval
+            .take_while(move |_| Ok(max_msg_timeout == 0 || delivered.elapsed() < Duration::from_millis(max_msg_timeout)))
+            .for_each(move |_| {
+                drop(consumer.touch_from(Some(origin), message_id));
+                Ok(())
+            });
+        self.handle.spawn(touches.select2(stopped).then(|_| Ok(())));
+
+        Some(stop)
+    }
+
+    // The connection a message has to be acknowledged on. Acknowledging it
+    // again, or after its connection was removed, fails.
+    fn origin(&self, from: Option<SocketAddr>, message_id: MessageId) -> Result<(SocketAddr, Connection), NsqError> {
+        let addr = match self.unacked.borrow().get(&message_id) {
+            Some(origins) => match from {
+                Some(from) => origins.iter().find(|&&origin| origin == from).cloned(),
+                None => origins.first().cloned(),
+            },
+            None => None,
+        };
+        addr.and_then(|addr| self.connections.borrow().get(&addr).map(|connection| (addr, connection.clone())))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("message {} is not in flight", message_id)).into())
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub(crate) fn acked(&self, origin: SocketAddr, message_id: MessageId) {
+        let mut unacked = self.unacked.borrow_mut();
+        let empty = match unacked.get_mut(&message_id) {
+            Some(origins) => {
+                origins.retain(|&addr| addr != origin);
+                origins.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            unacked.remove(&message_id);
+        }
+        drop(unacked);
+        self.check_drained();
+    }
+
+    // Left to time out, nsqd delivers it again. Until then its RDY slot
+    // can go to other connections.
+    pub(crate) fn ignored(&self, origin: SocketAddr, message_id: MessageId) {
+        if let Some(connection) = self.connections.borrow().get(&origin) {
+            drop(connection.inner.release());
+        }
+        self.acked(origin, message_id);
+    }
+
+    fn check_drained(&self) {
+        if self.unacked.borrow().is_empty() {
+            if let Some(drained) = self.drained.borrow_mut().take() {
+                let _ = drained.send(());
+            }
+        }
+    }
+
+    pub(crate) fn is_unacked(&self, origin: SocketAddr, message_id: MessageId) -> bool {
+        self.unacked.borrow().get(&message_id).is_some_and(|origins| origins.contains(&origin))
+    }
+
+    /// Features the first nsqd connected to agreed to, `None` when
+    /// `Config::feature_negotiation` is off or with nsqlookupd.
+    pub fn features(&self) -> Option<&NegotiatedFeatures> {
+        self.session.features.as_ref()
+    }
+
+    /// Identity of `Config::auth_secret`, `None` when nsqd did not require
+    /// AUTH.
+    pub fn identity(&self) -> Option<&AuthIdentity> {
+        self.session.identity.as_ref()
+    }
+
+    /// Change how many messages may be in flight at once, RDY is updated
+    /// on every connection.
+    pub fn set_max_in_flight(&self, max_in_flight: u64) {
+        self.rdy.set_max_in_flight(max_in_flight);
+    }
+
+    /// Replace `ExponentialBackoff`, the strategy deciding how long to
+    /// back off for after failures.
+    pub fn set_backoff_strategy<S: BackoffStrategy + 'static>(&self, strategy: S) {
+        self.rdy.set_backoff_strategy(Box::new(strategy));
+    }
+
+    /// Called with the messages given up on for exceeding
+    /// `Config::max_attempts`, before they are finished.
+    pub fn on_give_up<F: Fn(&response::Message) + 'static>(&self, hook: F) {
+        *self.give_up.borrow_mut() = Some(Rc::new(hook));
+    }
+
+    /// Stops consuming without messages being redelivered needlessly.
+    ///
+    /// Every connection gets RDY 0 and CLS, the `ResponseStream` ends once
+    /// nsqd confirmed with CLOSE_WAIT. Messages still in flight have until
+    /// `Config::drain_timeout` to be finished or requeued, the ones left
+    /// are requeued then. Resolves once every connection is closed, the
+    /// consumer is not reconnected or rebalanced afterwards.
+    pub fn stop(&self) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.stopped.set(true);
+        self.reconnecting.borrow_mut().clear();
+        self.rdy.stop();
+        // The forwarders keep theirs until their connection is closed.
+        let subscribed = self.deliveries.borrow_mut().take().is_some();
+
+        // nsqd refuses RDY and CLS before SUB.
+        let connections: Vec<Connection> = if subscribed {
+            self.connections.borrow().values().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        let closing: Vec<_> = connections.into_iter()
+            .map(|connection| {
+                let _ = connection.inner.send(Command::Rdy(0));
+                connection.inner.call(Command::Cls).then(|_| Ok(()))
+            })
+            .collect();
+
+        let consumer = self.clone();
+        let ret = future::join_all(closing)
+            .and_then(move |_: Vec<()>| consumer.drain().map(move |()| consumer))
+            .and_then(|consumer| {
+                consumer.requeue_unacked();
+                consumer.close()
+            });
+
+        Box::new(ret)
+    }
+
+    // Waits for the messages in flight to be acknowledged, for at most
+    // `Config::drain_timeout`.
+    fn drain(&self) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        if self.unacked.borrow().is_empty() {
+            return Box::new(future::ok(()));
+        }
+        let timeout = match Timeout::new(Duration::from_millis(self.config.drain_timeout), &self.handle) {
+            Ok(timeout) => timeout,
+            Err(err) => return Box::new(future::err(err.into())),
+        };
+
+        let (drained, done) = oneshot::channel();
+        *self.drained.borrow_mut() = Some(drained);
+        Box::new(done.select2(timeout).then(|_| Ok(())))
+    }
+
+    // Without delay, another consumer gets them right away.
+    fn requeue_unacked(&self) {
+        let unacked: Vec<_> = self.unacked.borrow().iter()
+            .map(|(&message_id, origins)| (message_id, origins.clone()))
+            .collect();
+        for (message_id, origins) in unacked {
+            for origin in origins {
+                drop(self.requeue_from(Some(origin), message_id, Duration::from_millis(0), false));
+            }
+        }
+    }
+
+    // Lets go of every connection, resolving once they are all closed.
+    fn close(&self) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        let connections = mem::take(&mut *self.connections.borrow_mut());
+        let closed: Vec<_> = connections.into_values()
+            .map(|connection| connection.closed.clone().then(|_| Ok(())))
+            .collect();
+
+        Box::new(future::join_all(closed).map(|_: Vec<()>| ()))
+    }
+
+    /// Observe connection events. Heartbeats are already answered by the
+    /// library, the hook is only informed of them.
+    pub fn on_event<F: Fn(ConnectionEvent) + 'static>(&self, hook: F) {
+        self.events.set(hook);
+    }
+}
+
+// For the commands nsqd does not answer, failures to process them arrive
+// with the messages.
+fn send(connection: &Connection, request: Command) -> Box<dyn Future<Item = (), Error = NsqError>> {
+    Box::new(future::result(connection.inner.send(request)))
+}
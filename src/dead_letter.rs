@@ -0,0 +1,110 @@
+//! Messages a consumer gave up on, as published to its dead-letter topic.
+
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use serde_json::{from_slice, to_vec};
+
+use error::NsqError;
+
+/// A message given up on, along with where it came from.
+///
+/// Published as a line of JSON metadata followed by the original body,
+/// which is left untouched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub channel: String,
+    pub attempts: u16,
+    /// Text of the last handler error, `None` when the message was given
+    /// up on before being handled.
+    pub error: Option<String>,
+    /// When nsqd first received the message.
+    pub first_seen: SystemTime,
+    pub body: Bytes,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    topic: String,
+    channel: String,
+    attempts: u16,
+    error: Option<String>,
+    // Nanoseconds since the epoch, like nsqd's message timestamps.
+    first_seen: u64,
+}
+
+impl DeadLetter {
+    pub fn encode(&self) -> Vec<u8> {
+        let first_seen = self.first_seen.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let metadata = Metadata {
+            topic: self.topic.clone(),
+            channel: self.channel.clone(),
+            attempts: self.attempts,
+            error: self.error.clone(),
+            first_seen: first_seen.as_nanos() as u64,
+        };
+
+        let mut data = to_vec(&metadata).unwrap();
+        data.push(b'\n');
+        data.extend_from_slice(&self.body);
+        data
+    }
+
+    /// Parses the body of a message received from a dead-letter topic.
+    pub fn decode(data: &[u8]) -> Result<DeadLetter, NsqError> {
+        let invalid = |reason: String| NsqError::from(io::Error::new(io::ErrorKind::InvalidData, reason));
+
+        let end = data.iter().position(|&byte| byte == b'\n')
+            .ok_or_else(|| invalid("dead letter without metadata".into()))?;
+        let metadata: Metadata = from_slice(&data[..end]).map_err(|err| invalid(err.to_string()))?;
+
+        Ok(DeadLetter {
+            topic: metadata.topic,
+            channel: metadata.channel,
+            attempts: metadata.attempts,
+            error: metadata.error,
+            first_seen: UNIX_EPOCH + Duration::from_nanos(metadata.first_seen),
+            body: Bytes::from(&data[end + 1..]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn letter(body: &[u8]) -> DeadLetter {
+        DeadLetter {
+            topic: "orders".into(),
+            channel: "billing".into(),
+            attempts: 5,
+            error: Some("card declined".into()),
+            first_seen: UNIX_EPOCH + Duration::from_nanos(1_500_000_000_123_456_789),
+            body: Bytes::from(body),
+        }
+    }
+
+    #[test]
+    fn metadata_comes_first() {
+        let expected = br#"{"topic":"orders","channel":"billing","attempts":5,"error":"card declined","first_seen":1500000000123456789}
+{"id":1}"#;
+        assert_eq!(letter(br#"{"id":1}"#).encode(), expected.to_vec());
+    }
+
+    #[test]
+    fn round_trips_any_body() {
+        let body = b"line one\nline two\n\x00\xff";
+        assert_eq!(DeadLetter::decode(&letter(body).encode()).unwrap(), letter(body));
+
+        let unhandled = DeadLetter { error: None, ..letter(b"") };
+        assert_eq!(DeadLetter::decode(&unhandled.encode()).unwrap(), unhandled);
+    }
+
+    #[test]
+    fn other_messages_are_rejected() {
+        assert!(DeadLetter::decode(b"just a body").is_err());
+        assert!(DeadLetter::decode(b"not json\nbody").is_err());
+    }
+}
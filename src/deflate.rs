@@ -0,0 +1,211 @@
+use std::io::{self, Read, Write};
+
+use bytes::BytesMut;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::{Async, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use compression::corrupt;
+
+const MAX_PENDING: usize = 65536;
+
+/// A connection compressed with raw deflate, which nsqd switches to once
+/// IDENTIFY agreed to deflate.
+///
+/// Writes are compressed as they come and sync flushed on `flush`, so nsqd
+/// can decode every command sent so far.
+pub struct DeflateStream<T> {
+    inner: T,
+    compress: Compress,
+    decompress: Decompress,
+    // Compressed data received but not decompressed yet.
+    read_buf: BytesMut,
+    // Compressed data not written to the connection yet.
+    write_buf: Vec<u8>,
+    // Whether data was compressed since the last sync flush.
+    unflushed: bool,
+}
+
+impl<T> DeflateStream<T> {
+    /// `buffered` is data already read from the connection, it belongs to
+    /// the compressed stream.
+    pub fn new(inner: T, level: u32, buffered: BytesMut) -> DeflateStream<T> {
+        DeflateStream {
+            inner,
+            compress: Compress::new(Compression::new(level), false),
+            decompress: Decompress::new(false),
+            read_buf: buffered,
+            write_buf: Vec::new(),
+            unflushed: false,
+        }
+    }
+
+    fn compress(&mut self, mut data: &[u8], mut flush: FlushCompress) -> io::Result<()> {
+        loop {
+            self.write_buf.reserve(data.len() / 2 + 1024);
+            let (before_in, before_out) = (self.compress.total_in(), self.compress.total_out());
+            self.compress.compress_vec(data, &mut self.write_buf, flush).map_err(corrupt)?;
+            data = &data[(self.compress.total_in() - before_in) as usize..];
+
+            if data.is_empty() {
+                // Output may still be pending, it is drained without
+                // asking for another flush.
+                if self.compress.total_out() == before_out {
+                    return Ok(());
+                }
+                flush = FlushCompress::None;
+            }
+        }
+    }
+}
+
+impl<T: Write> DeflateStream<T> {
+    fn write_compressed(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            let n = self.inner.write(&self.write_buf)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write deflate data"));
+            }
+            self.write_buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for DeflateStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let (before_in, before_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self.decompress.decompress(&self.read_buf, buf, FlushDecompress::None).map_err(corrupt)?;
+            self.read_buf.advance((self.decompress.total_in() - before_in) as usize);
+
+            let n = (self.decompress.total_out() - before_out) as usize;
+            if n > 0 || status == Status::StreamEnd {
+                return Ok(n);
+            }
+
+            let mut data = [0u8; 8192];
+            let n = self.inner.read(&mut data)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.read_buf.extend_from_slice(&data[..n]);
+        }
+    }
+}
+
+impl<T: Write> Write for DeflateStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Hold back a bounded amount, the rest waits for the connection.
+        if self.write_buf.len() >= MAX_PENDING {
+            self.write_compressed()?;
+        }
+
+        self.compress(buf, FlushCompress::None)?;
+        self.unflushed |= !buf.is_empty();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.unflushed {
+            self.compress(&[], FlushCompress::Sync)?;
+            self.unflushed = false;
+        }
+        self.write_compressed()?;
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for DeflateStream<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for DeflateStream<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.flush() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            res => res?,
+        }
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+    use compression::testing::{read_all, Trickle};
+
+    // Reads everything, the inner reader yields one byte at a time and
+    // blocks in between.
+    fn read_trickled(data: &[u8]) -> Vec<u8> {
+        read_all(DeflateStream::new(Trickle::new(data), 6, BytesMut::new()))
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut data = vec![b'a'; 200_000];
+        data.extend((0..5000u32).map(|i| (i * 7919 % 251) as u8));
+        data
+    }
+
+    #[test]
+    fn reads_reference_output() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&sample()).unwrap();
+        assert_eq!(read_trickled(&encoder.finish().unwrap()), sample());
+    }
+
+    #[test]
+    fn every_flush_makes_the_data_so_far_decodable() {
+        let mut stream = DeflateStream::new(Cursor::new(Vec::new()), 6, BytesMut::new());
+        let mut decompress = Decompress::new(false);
+        let mut decoded = Vec::with_capacity(300_000);
+        let mut sent = 0;
+
+        for data in &[&b"NOP\n"[..], &sample(), b"FIN 0123456789abcdef\n"] {
+            stream.write_all(data).unwrap();
+            stream.flush().unwrap();
+
+            let encoded = &stream.inner.get_ref()[sent..];
+            sent += encoded.len();
+            decoded.clear();
+            decompress.decompress_vec(encoded, &mut decoded, FlushDecompress::Sync).unwrap();
+            assert_eq!(&decoded[..], *data);
+        }
+
+        // Nothing new was written, nothing is sent.
+        stream.flush().unwrap();
+        assert_eq!(stream.inner.get_ref().len(), sent);
+        assert!(sent < sample().len() / 10);
+    }
+
+    #[test]
+    fn round_trips_through_the_reference() {
+        let mut stream = DeflateStream::new(Cursor::new(Vec::new()), 9, BytesMut::new());
+        stream.write_all(&sample()).unwrap();
+        stream.flush().unwrap();
+
+        let mut decoded = Vec::new();
+        let encoded = stream.inner.into_inner();
+        let _ = DeflateDecoder::new(&encoded[..]).read_to_end(&mut decoded);
+        assert_eq!(decoded, sample());
+        assert_eq!(read_trickled(&encoded), sample());
+    }
+
+    #[test]
+    fn buffered_data_is_read_first() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"OK").unwrap();
+        let encoded = encoder.finish().unwrap();
+        let (buffered, rest) = encoded.split_at(2);
+
+        let mut stream = DeflateStream::new(Cursor::new(rest.to_vec()), 6, BytesMut::from(buffered));
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"OK");
+    }
+}
@@ -1,37 +1,132 @@
 use std::io::Error as ioError;
+use std::io::ErrorKind;
 use std::fmt;
-use std::str;
 use std::error;
 
 #[derive(Debug)]
 pub enum NsqError {
     IOError(ioError),
+
+    // Error frames sent by nsqd, each carrying the server's text.
+    Invalid(String),
+    BadTopic(String),
+    BadChannel(String),
+    BadMessage(String),
+    PubFailed(String),
+    MpubFailed(String),
+    FinFailed(String),
+    ReqFailed(String),
+    TouchFailed(String),
+    AuthFailed(String),
+    Unauthorized(String),
+    // Any other error frame, kept verbatim.
+    Unknown(String),
+
+    // nsqd requires AUTH but `Config::auth_secret` is not set.
+    AuthRequired,
 }
 
-impl fmt::Display for NsqError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl NsqError {
+    /// Parses the payload of an error frame, e.g. `E_BAD_TOPIC PUB topic name "!" is not valid`.
+    pub fn from_frame(data: &str) -> NsqError {
+        let (code, text) = match data.find(' ') {
+            Some(pos) => (&data[..pos], data[pos + 1..].to_string()),
+            None => (data, String::new()),
+        };
+
+        match code {
+            "E_INVALID" => NsqError::Invalid(text),
+            "E_BAD_TOPIC" => NsqError::BadTopic(text),
+            "E_BAD_CHANNEL" => NsqError::BadChannel(text),
+            "E_BAD_MESSAGE" => NsqError::BadMessage(text),
+            "E_PUB_FAILED" => NsqError::PubFailed(text),
+            "E_MPUB_FAILED" => NsqError::MpubFailed(text),
+            "E_FIN_FAILED" => NsqError::FinFailed(text),
+            "E_REQ_FAILED" => NsqError::ReqFailed(text),
+            "E_TOUCH_FAILED" => NsqError::TouchFailed(text),
+            "E_AUTH_FAILED" => NsqError::AuthFailed(text),
+            "E_UNAUTHORIZED" => NsqError::Unauthorized(text),
+            _ => NsqError::Unknown(data.to_string()),
+        }
+    }
+
+    /// The protocol error code, `None` for errors not sent by nsqd.
+    pub fn code(&self) -> Option<&'static str> {
         match *self {
-            NsqError::IOError(ref err) => write!(f, "IO error: {}", err),
+            NsqError::IOError(_) => None,
+            NsqError::Invalid(_) => Some("E_INVALID"),
+            NsqError::BadTopic(_) => Some("E_BAD_TOPIC"),
+            NsqError::BadChannel(_) => Some("E_BAD_CHANNEL"),
+            NsqError::BadMessage(_) => Some("E_BAD_MESSAGE"),
+            NsqError::PubFailed(_) => Some("E_PUB_FAILED"),
+            NsqError::MpubFailed(_) => Some("E_MPUB_FAILED"),
+            NsqError::FinFailed(_) => Some("E_FIN_FAILED"),
+            NsqError::ReqFailed(_) => Some("E_REQ_FAILED"),
+            NsqError::TouchFailed(_) => Some("E_TOUCH_FAILED"),
+            NsqError::AuthFailed(_) => Some("E_AUTH_FAILED"),
+            NsqError::Unauthorized(_) => Some("E_UNAUTHORIZED"),
+            NsqError::Unknown(_) |
+            NsqError::AuthRequired => None,
         }
     }
+
+    /// Whether nsqd drops the connection after sending this error.
+    ///
+    /// Only a failed FIN, REQ or TOUCH leaves the connection usable, as
+    /// they just mean the message is no longer in flight.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(*self, NsqError::FinFailed(_) | NsqError::ReqFailed(_) | NsqError::TouchFailed(_))
+    }
 }
 
-impl error::Error for NsqError {
-    fn description(&self) -> &str {
+impl fmt::Display for NsqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            NsqError::IOError(ref err) => err.description(),
+            NsqError::IOError(ref err) => write!(f, "IO error: {}", err),
+            NsqError::Unknown(ref text) => write!(f, "{}", text),
+            NsqError::AuthRequired => write!(f, "nsqd requires authentication but no secret is set"),
+            NsqError::Invalid(ref text) |
+            NsqError::BadTopic(ref text) |
+            NsqError::BadChannel(ref text) |
+            NsqError::BadMessage(ref text) |
+            NsqError::PubFailed(ref text) |
+            NsqError::MpubFailed(ref text) |
+            NsqError::FinFailed(ref text) |
+            NsqError::ReqFailed(ref text) |
+            NsqError::TouchFailed(ref text) |
+            NsqError::AuthFailed(ref text) |
+            NsqError::Unauthorized(ref text) => {
+                write!(f, "{} {}", self.code().unwrap_or_default(), text)
+            }
         }
     }
+}
 
-    fn cause(&self) -> Option<&error::Error> {
+impl error::Error for NsqError {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
-            NsqError::IOError(ref err) => Some(err),          
+            NsqError::IOError(ref err) => Some(err),
+            _ => None,
         }
     }
 }
 
 impl From<ioError> for NsqError {
     fn from(err: ioError) -> NsqError {
+        // Protocol errors passed along as io::Error are unwrapped again.
+        if err.get_ref().is_some_and(|inner| inner.is::<NsqError>()) {
+            let inner = err.into_inner().unwrap();
+            return *inner.downcast::<NsqError>().unwrap();
+        }
         NsqError::IOError(err)
     }
-}
\ No newline at end of file
+}
+
+impl From<NsqError> for ioError {
+    fn from(err: NsqError) -> ioError {
+        match err {
+            NsqError::IOError(err) => err,
+            err => ioError::new(ErrorKind::InvalidData, err),
+        }
+    }
+}
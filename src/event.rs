@@ -0,0 +1,44 @@
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Things happening on an nsqd connection that are handled by the library
+/// but can be observed through a hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// nsqd sent a heartbeat, it was answered with NOP.
+    Heartbeat,
+    /// Nothing was received for two heartbeat intervals, the connection
+    /// is being closed.
+    Stalled,
+    /// The connection to nsqd was lost.
+    Disconnected(SocketAddr),
+    /// Connecting to nsqd again, after `Config::reconnect_delay` doubled
+    /// for every attempt that failed.
+    Reconnecting(SocketAddr),
+    /// Connected to nsqd again, consumers subscribed with their RDY back.
+    Reconnected(SocketAddr),
+}
+
+type Hook = Rc<dyn Fn(ConnectionEvent)>;
+
+/// Slot shared between a client and its transport, holding the hook set
+/// by the user.
+#[derive(Clone, Default)]
+pub(crate) struct EventHook {
+    inner: Rc<RefCell<Option<Hook>>>,
+}
+
+impl EventHook {
+    pub fn set<F: Fn(ConnectionEvent) + 'static>(&self, hook: F) {
+        *self.inner.borrow_mut() = Some(Rc::new(hook));
+    }
+
+    pub fn emit(&self, event: ConnectionEvent) {
+        // Release the borrow before calling, the hook may replace itself.
+        let hook = self.inner.borrow().clone();
+        if let Some(hook) = hook {
+            hook(event);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Processing of messages for `Consumer::add_handler`.
+
+use std::fmt;
+
+use futures::{Future, IntoFuture};
+
+use response::Message;
+
+/// Processes a message, which the consumer finishes once the returned
+/// future succeeded and requeues when it failed.
+///
+/// Closures returning something `IntoFuture` are handlers too.
+pub trait Handler {
+    type Error: fmt::Display;
+    type Future: Future<Item = (), Error = Self::Error>;
+
+    fn handle(&self, message: &Message) -> Self::Future;
+}
+
+impl<F, R> Handler for F
+    where F: Fn(&Message) -> R,
+          R: IntoFuture<Item = ()>,
+          R::Error: fmt::Display
+{
+    type Error = R::Error;
+    type Future = R::Future;
+
+    fn handle(&self, message: &Message) -> R::Future {
+        self(message).into_future()
+    }
+}
@@ -0,0 +1,121 @@
+use std::cmp;
+
+use config::Config;
+
+// Tells nsqd to turn heartbeats or output buffering off.
+const DISABLED: i64 = -1;
+
+/// Body of IDENTIFY, with the field names and values nsqd documents.
+///
+/// Built from `Config`, which keeps settings nsqd does not need to see
+/// (TLS verification, the AUTH secret) out of it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Identify<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<&'a str>,
+    pub user_agent: &'a str,
+    pub feature_negotiation: bool,
+    // Milliseconds, -1 disables heartbeats.
+    pub heartbeat_interval: i64,
+    // Bytes, -1 disables output buffering.
+    pub output_buffer_size: i64,
+    // Milliseconds, -1 disables the output buffer timeout.
+    pub output_buffer_timeout: i64,
+    pub tls_v1: bool,
+    pub snappy: bool,
+    pub deflate: bool,
+    pub deflate_level: u16,
+    pub sample_rate: u16,
+    // Milliseconds, 0 leaves nsqd's default.
+    pub msg_timeout: u32,
+}
+
+impl<'a> From<&'a Config> for Identify<'a> {
+    fn from(config: &'a Config) -> Identify<'a> {
+        Identify {
+            client_id: config.client_id.as_deref(),
+            hostname: config.hostname.as_deref(),
+            user_agent: &config.user_agent,
+            feature_negotiation: config.feature_negotiation,
+            heartbeat_interval: disabled_below_zero(config.heartbeat_interval),
+            output_buffer_size: disabled_below_zero(config.output_buffer_size),
+            output_buffer_timeout: disabled_below_zero(config.output_buffer_timeout),
+            tls_v1: config.tls_v1,
+            snappy: config.snappy,
+            deflate: config.deflate,
+            deflate_level: config.deflate_level,
+            // nsqd samples 0-99 percent of the messages, 0 meaning all.
+            sample_rate: cmp::min(config.sample_rate, 99),
+            msg_timeout: config.message_timeout,
+        }
+    }
+}
+
+fn disabled_below_zero(value: i64) -> i64 {
+    cmp::max(value, DISABLED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::to_string;
+
+    fn config() -> Config {
+        Config {
+            client_id: Some("worker-1".into()),
+            hostname: Some("worker-1.example.com".into()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let expected = r#"{"client_id":"worker-1","hostname":"worker-1.example.com","user_agent":"github.com/wisespace-io/nsqueue","feature_negotiation":true,"heartbeat_interval":30000,"output_buffer_size":16384,"output_buffer_timeout":250,"tls_v1":false,"snappy":false,"deflate":false,"deflate_level":6,"sample_rate":0,"msg_timeout":0}"#;
+        assert_eq!(to_string(&Identify::from(&config())).unwrap(), expected);
+    }
+
+    #[test]
+    fn everything_set() {
+        let config = Config {
+            user_agent: "app/1.0".into(),
+            heartbeat_interval: 5000,
+            message_timeout: 120_000,
+            output_buffer_size: 65536,
+            output_buffer_timeout: 100,
+            sample_rate: 25,
+            ..config().deflate(3).auth_secret("s3cr3t".into())
+        };
+        let expected = r#"{"client_id":"worker-1","hostname":"worker-1.example.com","user_agent":"app/1.0","feature_negotiation":true,"heartbeat_interval":5000,"output_buffer_size":65536,"output_buffer_timeout":100,"tls_v1":false,"snappy":false,"deflate":true,"deflate_level":3,"sample_rate":25,"msg_timeout":120000}"#;
+        assert_eq!(to_string(&Identify::from(&config)).unwrap(), expected);
+    }
+
+    #[test]
+    fn negative_values_disable() {
+        let config = Config {
+            heartbeat_interval: -1,
+            output_buffer_size: -1,
+            output_buffer_timeout: -250,
+            ..config()
+        };
+        let identify = Identify::from(&config);
+        assert_eq!(identify.heartbeat_interval, -1);
+        assert_eq!(identify.output_buffer_size, -1);
+        assert_eq!(identify.output_buffer_timeout, -1);
+    }
+
+    #[test]
+    fn unset_identifiers_are_left_out() {
+        let config = Config { client_id: None, hostname: None, ..Config::default() }.tls(Default::default());
+        let json = to_string(&Identify::from(&config)).unwrap();
+        assert!(json.starts_with(r#"{"user_agent":"github.com/wisespace-io/nsqueue","feature_negotiation":true"#));
+        assert!(json.contains(r#""tls_v1":true"#));
+    }
+
+    #[test]
+    fn sample_rate_is_capped() {
+        let config = Config { sample_rate: 150, ..config() };
+        assert_eq!(Identify::from(&config).sample_rate, 99);
+    }
+}
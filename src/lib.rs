@@ -4,21 +4,38 @@ extern crate futures;
 extern crate log;
 extern crate tokio_io;
 extern crate tokio_core;
-extern crate tokio_service;
-extern crate tokio_proto;
 extern crate bytes;
 extern crate byteorder;
 extern crate hostname;
 extern crate tokio_codec;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate snap;
+extern crate crc32c;
+extern crate flate2;
+extern crate rand;
 
 #[macro_use]
 extern crate serde_derive;
 
+mod client;
 mod codec;
 mod commands;
+mod compression;
+mod deflate;
+mod identify;
+mod lookup;
 mod protocol;
+mod rdy;
+mod snappy;
+mod tls;
+mod transport;
+pub mod backoff;
+pub mod event;
 pub mod response;
 pub mod error;
 pub mod config;
+pub mod dead_letter;
+pub mod handler;
 pub mod consumer;
 pub mod producer;
\ No newline at end of file
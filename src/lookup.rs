@@ -0,0 +1,227 @@
+//! Asks nsqlookupd which nsqd instances have a topic.
+//!
+//! Only `/lookup` is needed, over plain HTTP/1.0 so the response simply
+//! ends when nsqlookupd closes the connection.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str;
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use serde_json::from_slice;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_to_end, write_all};
+
+use error::NsqError;
+use protocol;
+
+// Port of nsqlookupd's HTTP interface when the address leaves it out.
+const DEFAULT_HTTP_PORT: u16 = 4161;
+
+/// An nsqlookupd, as given by `http://host:port` or `host:port`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lookupd {
+    // Resolved for every query, like the nsqd instances it lists.
+    name: String,
+    port: u16,
+    host: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Lookup {
+    producers: Vec<Peer>,
+    // nsqlookupd before 1.0 wraps the answer.
+    data: Option<Box<Lookup>>,
+}
+
+#[derive(Deserialize)]
+struct Peer {
+    broadcast_address: String,
+    tcp_port: u16,
+}
+
+impl Lookupd {
+    pub fn parse(url: &str) -> Result<Lookupd, NsqError> {
+        let invalid = || NsqError::from(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid nsqlookupd address {}", url)));
+
+        let host = if let Some(rest) = url.strip_prefix("http://") {
+            rest
+        } else if url.contains("://") {
+            return Err(invalid());
+        } else {
+            url
+        };
+        let host = host.split('/').next().unwrap_or_default();
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        // IPv6 addresses are only followed by a port in brackets.
+        let (name, port) = match host.rfind(':') {
+            Some(colon) if !host.ends_with(']') && (host.starts_with('[') || !host[..colon].contains(':')) => {
+                (&host[..colon], host[colon + 1..].parse().map_err(|_| invalid())?)
+            }
+            _ => (host, DEFAULT_HTTP_PORT),
+        };
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+        if name.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Lookupd { name: name.to_string(), port, host: host.to_string() })
+    }
+
+    /// Resolves to the TCP addresses of the nsqd instances producing
+    /// `topic`, by their broadcast_address. Fails when nsqlookupd did not
+    /// answer within `timeout`.
+    pub fn lookup(&self, topic: &str, timeout: Duration, handle: &Handle) -> Box<dyn Future<Item = Vec<SocketAddr>, Error = NsqError>> {
+        let request = format!(
+            "GET /lookup?topic={} HTTP/1.0\r\nHost: {}\r\nAccept: application/vnd.nsq; version=1.0\r\n\r\n",
+            encode(topic), self.host
+        );
+
+        let connecting = handle.clone();
+        let answered = resolve(self.name.clone(), self.port)
+            .and_then(move |addr| {
+                TcpStream::connect(&addr, &connecting)
+                    .and_then(move |io| write_all(io, request))
+                    .and_then(|(io, _)| read_to_end(io, Vec::new()))
+                    .map_err(NsqError::from)
+            })
+            .and_then(|(_, response)| {
+                // Those that do not resolve are skipped.
+                let resolved = producers(&response)?.into_iter()
+                    .map(|(name, port)| resolve(name, port).then(|res| Ok::<_, NsqError>(res.ok())));
+                Ok(future::join_all(resolved))
+            })
+            .flatten()
+            .map(|addrs| addrs.into_iter().flatten().collect());
+        let ret = protocol::within(answered, timeout, handle, "nsqlookupd");
+
+        Box::new(ret)
+    }
+}
+
+// Resolving blocks, so names are resolved on a thread of their own rather
+// than on the reactor, where it would hold up every connection.
+fn resolve(name: String, port: u16) -> Box<dyn Future<Item = SocketAddr, Error = NsqError>> {
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return Box::new(future::ok(SocketAddr::new(ip, port)));
+    }
+
+    let (sender, resolved) = oneshot::channel();
+    thread::spawn(move || {
+        let addr = (name.as_str(), port).to_socket_addrs()
+            .and_then(|mut addrs| addrs.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", name))));
+        drop(sender.send(addr));
+    });
+    let ret = resolved.then(|res| match res {
+        Ok(res) => res.map_err(NsqError::from),
+        Err(_) => Err(io::Error::other("resolving stopped").into()),
+    });
+
+    Box::new(ret)
+}
+
+// An unknown topic is answered with 404, it just has no producers yet.
+// Producers are given by broadcast_address and TCP port.
+fn producers(response: &[u8]) -> Result<Vec<(String, u16)>, NsqError> {
+    let invalid = |reason: String| NsqError::from(io::Error::new(io::ErrorKind::InvalidData, reason));
+
+    let end = response.windows(4).position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete response from nsqlookupd".into()))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    match status {
+        "200" => (),
+        "404" => return Ok(Vec::new()),
+        _ => return Err(invalid(format!("nsqlookupd answered {}", status))),
+    }
+
+    let lookup: Lookup = from_slice(&response[end + 4..]).map_err(|err| invalid(err.to_string()))?;
+    let lookup = match lookup.data {
+        Some(data) => *data,
+        None => lookup,
+    };
+
+    let producers = lookup.producers.into_iter()
+        .map(|peer| (peer.broadcast_address, peer.tcp_port))
+        .collect();
+    Ok(producers)
+}
+
+// Percent-encodes what topic names may contain beyond the unreserved
+// characters, like the `#` of ephemeral topics.
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_with_and_without_scheme() {
+        let lookupd = Lookupd::parse("http://127.0.0.1:4161/").unwrap();
+        assert_eq!((lookupd.name.as_str(), lookupd.port), ("127.0.0.1", 4161));
+        assert_eq!(lookupd.host, "127.0.0.1:4161");
+        let named = Lookupd::parse("lookupd.invalid:5161").unwrap();
+        assert_eq!((named.name.as_str(), named.port), ("lookupd.invalid", 5161));
+        assert_eq!(Lookupd::parse("127.0.0.1").unwrap().port, 4161);
+
+        let v6 = Lookupd::parse("http://[::1]:5161").unwrap();
+        assert_eq!((v6.name.as_str(), v6.port), ("::1", 5161));
+        assert_eq!(Lookupd::parse("[::1]").unwrap().port, 4161);
+        assert_eq!(Lookupd::parse("::1").unwrap().name, "::1");
+
+        assert!(Lookupd::parse("https://127.0.0.1:4161").is_err());
+        assert!(Lookupd::parse("http://").is_err());
+        assert!(Lookupd::parse("127.0.0.1:http").is_err());
+    }
+
+    #[test]
+    fn producers_are_found_by_broadcast_address() {
+        let current = b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n\
+            {\"channels\":[],\"producers\":[{\"remote_address\":\"10.0.0.1:51234\",\"hostname\":\"nowhere.invalid\",\
+            \"broadcast_address\":\"127.0.0.1\",\"tcp_port\":4150,\"http_port\":4151,\"version\":\"1.2.1\"}]}";
+        assert_eq!(producers(current).unwrap(), vec![("127.0.0.1".to_string(), 4150)]);
+
+        let wrapped = b"HTTP/1.0 200 OK\r\n\r\n\
+            {\"status_code\":200,\"status_txt\":\"OK\",\"data\":{\"producers\":[{\"broadcast_address\":\"127.0.0.2\",\"tcp_port\":4150}]}}";
+        assert_eq!(producers(wrapped).unwrap(), vec![("127.0.0.2".to_string(), 4150)]);
+    }
+
+    #[test]
+    fn unknown_topics_have_no_producers() {
+        let not_found = b"HTTP/1.0 404 Not Found\r\n\r\n{\"message\":\"TOPIC_NOT_FOUND\"}";
+        assert_eq!(producers(not_found).unwrap(), vec![]);
+
+        assert!(producers(b"HTTP/1.0 500 Internal Server Error\r\n\r\n").is_err());
+        assert!(producers(b"HTTP/1.0 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn names_are_resolved_like_addresses() {
+        assert_eq!(resolve("127.0.0.1".into(), 4150).wait().unwrap(), "127.0.0.1:4150".parse().unwrap());
+        assert!(resolve("localhost".into(), 4150).wait().unwrap().ip().is_loopback());
+        assert!(resolve("nowhere.invalid".into(), 4150).wait().is_err());
+    }
+
+    #[test]
+    fn topics_are_percent_encoded() {
+        assert_eq!(encode("events.v2_raw-1"), "events.v2_raw-1");
+        assert_eq!(encode("clicks#ephemeral"), "clicks%23ephemeral");
+    }
+}
@@ -1,75 +1,202 @@
-use futures::{Future};
+use bytes::Bytes;
+use futures::{Future, future};
+use futures::sync::oneshot;
 
-use tokio_service::Service;
-use tokio_core::reactor::Handle;
-
-use tokio_proto::{TcpClient};
-use tokio_proto::streaming::{Message};
-use tokio_proto::util::client_proxy::ClientProxy;
+use tokio_core::reactor::{Handle, Timeout};
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io;
+use std::mem;
 use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
 
 use config::Config;
-use codec::{NsqMessage, NsqResponseMessage, ClientTypeMap};
-use protocol::{NsqProtocol, RequestMessage};
+use error::NsqError;
+use event::{ConnectionEvent, EventHook};
+use response::{NegotiatedFeatures, AuthIdentity};
+use client::NsqClient;
+use commands::Command;
+use protocol::{self, Closed, Session};
 
+#[derive(Clone)]
 pub struct Producer {
-    inner: ClientTypeMap<ClientProxy<NsqMessage, NsqResponseMessage, io::Error>>,
+    link: Rc<RefCell<Link>>,
+    events: EventHook,
+    session: Session,
+    queue_size: usize,
+}
+
+// The connection to nsqd, or what is published until it is back.
+enum Link {
+    Connected(NsqClient),
+    Reconnecting(VecDeque<Queued>),
+    Disconnected,
+}
+
+type Queued = (Command, oneshot::Sender<Result<String, NsqError>>);
+
+// What reconnecting needs.
+#[derive(Clone)]
+struct Target {
+    addr: SocketAddr,
+    handle: Handle,
+    config: Config,
+    events: EventHook,
 }
 
 impl Producer {
-    /// Establish a connection and send protocol version.
-    pub fn connect(addr: &SocketAddr, handle: &Handle, config: Config) -> Box<Future<Item = Producer, Error = io::Error>> {
-        let protocol = NsqProtocol::new(config);
-        let ret = TcpClient::new(protocol)
-            .connect(addr, handle)
-            .map(|client_proxy| {
-                let type_map = ClientTypeMap { inner: client_proxy };
-                Producer { inner: type_map }
+    /// Establish a connection and identify. Resolves once nsqd accepted
+    /// the IDENTIFY.
+    pub fn connect(addr: &SocketAddr, handle: &Handle, config: Config) -> Box<dyn Future<Item = Producer, Error = NsqError>> {
+        let events = EventHook::default();
+        let target = Target { addr: *addr, handle: handle.clone(), config: config.clone(), events: events.clone() };
+        let ret = protocol::connect(addr, handle, config.clone(), events.clone(), None)
+            .map(move |(client, session, closed)| {
+                let link = Rc::new(RefCell::new(Link::Connected(client)));
+                watch(Rc::downgrade(&link), closed, target);
+                Producer { link, events, session, queue_size: config.publish_queue_size }
             });
 
         Box::new(ret)
     }
 
     // Publish a message to a topic
-    pub fn publish(&self, topic: String, message: String) -> Box<Future<Item = NsqResponseMessage, Error = io::Error>> {
-        let mut request = RequestMessage::new();
-        request.create_pub_command(topic, message);        
-        
+    pub fn publish(&self, topic: String, message: impl Into<Bytes>) -> Box<dyn Future<Item = String, Error = NsqError>> {
+        let request = Command::Pub { topic, body: message.into() };
+
         self.handler(request)
     }
 
     // Publish multiple messages to a topic (atomically)
-    pub fn mpublish(&self, topic: String, messages: Vec<String>) -> Box<Future<Item = NsqResponseMessage, Error = io::Error>> {
-        let mut request = RequestMessage::new();
-        request.create_mpub_command(topic, messages);        
-        
+    pub fn mpublish<M: Into<Bytes>>(&self, topic: String, messages: Vec<M>) -> Box<dyn Future<Item = String, Error = NsqError>> {
+        let bodies = messages.into_iter().map(Into::into).collect();
+        let request = Command::Mpub { topic, bodies };
+
         self.handler(request)
-    } 
+    }
+
+    // Publish a deferred message to a topic, defer_time is in milliseconds
+    pub fn dpublish(&self, topic: String, message: impl Into<Bytes>, defer_time: i64) -> Box<dyn Future<Item = String, Error = NsqError>> {
+        if defer_time < 0 {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "defer_time must not be negative");
+            return Box::new(future::err(err.into()));
+        }
+        let request = Command::Dpub { topic, defer_ms: defer_time as u64, body: message.into() };
 
-    // Publish a deferred message to a topic
-    pub fn dpublish(&self, topic: String, message: String, defer_time: i64) -> Box<Future<Item = NsqResponseMessage, Error = io::Error>> {
-        let mut request = RequestMessage::new();
-        request.create_dpub_command(topic, message, defer_time);
-        
         self.handler(request)
     }
 
-    fn handler(&self, request: RequestMessage) -> Box<Future<Item = NsqResponseMessage, Error = io::Error>> {
-        let service = self.inner.clone();
-        let resp = service.inner.call(Message::WithoutBody(request))
-            .map_err(|e| {
-                 e.into()}
-                )
-            .and_then(|resp| {
-                if resp != "OK".into() {
-                    Err(io::Error::new(io::ErrorKind::Other, "expected OK"))
-                } else {
-                    Ok(resp)
-                }
-            });
+    /// Features nsqd agreed to, `None` when `Config::feature_negotiation`
+    /// is off.
+    pub fn features(&self) -> Option<&NegotiatedFeatures> {
+        self.session.features.as_ref()
+    }
+
+    /// Identity of `Config::auth_secret`, `None` when nsqd did not require
+    /// AUTH.
+    pub fn identity(&self) -> Option<&AuthIdentity> {
+        self.session.identity.as_ref()
+    }
 
-        Box::new(resp)        
+    /// Observe connection events. Heartbeats are already answered by the
+    /// library, the hook is only informed of them. Losing the connection
+    /// and reconnecting are reported too.
+    pub fn on_event<F: Fn(ConnectionEvent) + 'static>(&self, hook: F) {
+        self.events.set(hook);
     }
-}
\ No newline at end of file
+
+    // While reconnecting, up to `Config::publish_queue_size` requests wait
+    // for the connection, the rest fail right away.
+    fn handler(&self, request: Command) -> Box<dyn Future<Item = String, Error = NsqError>> {
+        match *self.link.borrow_mut() {
+            Link::Connected(ref client) => call(client, request),
+            Link::Reconnecting(ref mut queued) if queued.len() < self.queue_size => {
+                let (sender, resp) = oneshot::channel();
+                queued.push_back((request, sender));
+                Box::new(resp.then(|resp| resp.unwrap_or_else(|_| Err(not_connected()))))
+            }
+            _ => Box::new(future::err(not_connected())),
+        }
+    }
+}
+
+// E_BAD_TOPIC, E_PUB_FAILED, ... fail the call.
+fn call(client: &NsqClient, request: Command) -> Box<dyn Future<Item = String, Error = NsqError>> {
+    let resp = client.call(request)
+        .and_then(|resp| {
+            if resp != "OK" {
+                Err(io::Error::other("expected OK").into())
+            } else {
+                Ok(resp)
+            }
+        });
+
+    Box::new(resp)
+}
+
+fn not_connected() -> NsqError {
+    io::Error::new(io::ErrorKind::NotConnected, "not connected to nsqd").into()
+}
+
+// Reconnects once the connection closed, as long as the producer is used.
+fn watch(link: Weak<RefCell<Link>>, closed: Closed, target: Target) {
+    let handle = target.handle.clone();
+    handle.spawn(closed.then(move |_| {
+        if let Some(current) = link.upgrade() {
+            let reconnecting = target.config.reconnect_delay > 0;
+            *current.borrow_mut() = if reconnecting {
+                Link::Reconnecting(VecDeque::new())
+            } else {
+                Link::Disconnected
+            };
+            target.events.emit(ConnectionEvent::Disconnected(target.addr));
+            if reconnecting {
+                reconnect(link, target, 0);
+            }
+        }
+        Ok(())
+    }));
+}
+
+// Waits longer after every failed attempt. Once connected the queued
+// requests are sent in order.
+fn reconnect(link: Weak<RefCell<Link>>, target: Target, attempt: u32) {
+    let timeout = match Timeout::new(protocol::reconnect_delay(&target.config, attempt), &target.handle) {
+        Ok(timeout) => timeout,
+        Err(_) => return,
+    };
+
+    let handle = target.handle.clone();
+    handle.spawn(timeout.then(move |_| {
+        if link.upgrade().is_none() {
+            return Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _>>;
+        }
+        target.events.emit(ConnectionEvent::Reconnecting(target.addr));
+
+        let connected = protocol::connect(&target.addr, &target.handle, target.config.clone(), target.events.clone(), None);
+        Box::new(connected.then(move |res| {
+            let current = match link.upgrade() {
+                Some(current) => current,
+                None => return Ok(()),
+            };
+            match res {
+                Ok((client, _, closed)) => {
+                    let previous = mem::replace(&mut *current.borrow_mut(), Link::Connected(client.clone()));
+                    if let Link::Reconnecting(queued) = previous {
+                        for (request, sender) in queued {
+                            target.handle.spawn(call(&client, request).then(move |resp| {
+                                drop(sender.send(resp));
+                                Ok(())
+                            }));
+                        }
+                    }
+                    target.events.emit(ConnectionEvent::Reconnected(target.addr));
+                    watch(link, closed, target);
+                }
+                Err(_) => reconnect(link, target, attempt + 1),
+            }
+            Ok(())
+        }))
+    }));
+}
@@ -1,130 +1,306 @@
+use std::cmp;
 use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
 
+use tokio_codec::{Decoder, Framed, FramedParts};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::codec::{Framed};
-//use tokio_codec::{Framed};
-use tokio_proto::streaming::pipeline::{Frame, ClientProto};
+use tokio_io::io::write_all;
 
-use serde_json::{to_string};
+use serde_json::{from_str, to_vec};
 
-use futures::{Future, Stream, Sink};
+use futures::{future, Future, Stream, Sink};
+use futures::future::Either;
+use futures::sync::oneshot;
 
-use commands::*;
-use response::Message;
-use codec::NsqCodec;
+use backoff::{BackoffStrategy, ExponentialBackoff};
+use client::NsqClient;
+use commands::{self, Command};
+use codec::{NsqCodec, NsqFrame};
 use config::Config;
+use error::NsqError;
+use event::EventHook;
+use identify::Identify;
+use rdy::RdyControl;
+use response::{AuthIdentity, NegotiatedFeatures};
+use deflate::DeflateStream;
+use snappy::SnappyStream;
+use tls;
+use transport::NsqTransport;
 
-/// Protocol definition
-pub struct NsqProtocol {
-    pub config: Config,
+// Level nsqd uses when IDENTIFY asks for none.
+const DEFAULT_DEFLATE_LEVEL: u16 = 6;
+
+/// Resolves, as canceled, once the connection is gone.
+pub type Closed = oneshot::Receiver<()>;
+
+/// Connection the transport runs on, plain or upgraded to TLS.
+pub trait Io: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> Io for T {}
+
+pub type BoxedTransport = NsqTransport<Box<dyn Io>>;
+
+type BoxedFramed = Framed<Box<dyn Io>, NsqCodec>;
+
+type Handshake<I> = Box<dyn Future<Item = I, Error = NsqError>>;
+
+/// What the handshake established, kept by the client.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    // Features nsqd agreed to, `None` when they were not negotiated.
+    pub features: Option<NegotiatedFeatures>,
+    // Identity of the AUTH secret, `None` when nsqd did not require AUTH.
+    pub identity: Option<AuthIdentity>,
 }
 
-impl NsqProtocol {
-    pub fn new(config: Config) -> Self {
-        NsqProtocol {
-            config: config,
-        }
+/// Connects to nsqd and runs the handshake before handing the connection
+/// to the client, so failures reach the caller. Resolves to the client
+/// along with what the handshake established and when the connection
+/// closed. Fails when nsqd did not finish the handshake within
+/// `Config::dial_timeout`.
+pub fn connect(addr: &SocketAddr, handle: &Handle, config: Config, events: EventHook, rdy: Option<RdyControl>)
+    -> Handshake<(NsqClient, Session, Closed)>
+{
+    if config.snappy && config.deflate {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "snappy and deflate can not be used together");
+        return Box::new(future::err(err.into()));
     }
+
+    let timeout = Duration::from_millis(config.dial_timeout);
+    let connecting = handle.clone();
+    let server_name = addr.ip().to_string();
+    let handshaken = TcpStream::connect(addr, handle)
+        .map_err(NsqError::from)
+        .and_then(move |io| handshake(io, config, server_name, &connecting, events, rdy));
+    let handle = handle.clone();
+    let ret = within(handshaken, timeout, &handle, "nsqd").map(move |(mut transport, session)| {
+        let closed = transport.closed();
+        (NsqClient::spawn(transport, &handle), session, closed)
+    });
+
+    Box::new(ret)
 }
 
-#[allow(unused_variables)]
-impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for NsqProtocol {
-    type Request = RequestMessage;
-    type RequestBody = RequestMessage;
-    type Response = String;
-    type ResponseBody = Message;
-    
-    type Error = io::Error;
-    type Transport = Framed<T, NsqCodec>;
-    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
-
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        let config = self.config.clone();
-        let mut request = RequestMessage::new();
-        request.set_protocol_version(commands::VERSION_2);
-                
-        let codec = NsqCodec {
-            decoding_head: true,
-        };
-
-        // Send protocol version
-        let tst = request.clone();
-        let version = Frame::Message {message: tst, body: false };
-        let handshake = io.framed(codec).send(version)
-        .and_then(move |transport| {
-            let mut request = RequestMessage::new();
-            request.create_identify_command(config);
-            
+/// How long to wait before connecting again after `attempt` attempts
+/// failed, `Config::reconnect_delay` doubled for each of them.
+pub fn reconnect_delay(config: &Config, attempt: u32) -> Duration {
+    let delay = ExponentialBackoff.calculate(attempt, Duration::from_millis(config.reconnect_delay));
+    cmp::min(delay, Duration::from_millis(config.max_reconnect_delay))
+}
+
+/// Fails with `TimedOut` unless `future` resolves within `timeout`,
+/// saying that `peer` did not answer.
+pub fn within<F>(future: F, timeout: Duration, handle: &Handle, peer: &'static str) -> Box<dyn Future<Item = F::Item, Error = NsqError>>
+    where F: Future<Error = NsqError> + 'static
+{
+    let expired = match Timeout::new(timeout, handle) {
+        Ok(expired) => expired,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+    let ret = future.select2(expired).then(move |res| match res {
+        Ok(Either::A((item, _))) => Ok(item),
+        Ok(Either::B(_)) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} did not answer", peer)).into()),
+        Err(Either::A((err, _))) => Err(err),
+        Err(Either::B((err, _))) => Err(err.into()),
+    });
+
+    Box::new(ret)
+}
+
+/// Sends the protocol version and IDENTIFY, upgrades the connection to
+/// what nsqd agreed to and authenticates when required. `server_name` is
+/// the name TLS verifies. Consumers pass the `RdyControl` the connection
+/// joins.
+pub fn handshake<T>(io: T, config: Config, server_name: String, handle: &Handle, events: EventHook, rdy: Option<RdyControl>)
+    -> Handshake<(BoxedTransport, Session)>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let handle = handle.clone();
+
+    // Send protocol version
+    let handshake = write_all(io, commands::VERSION_2)
+        .map_err(NsqError::from)
+        .and_then(move |(io, _)| {
+            let identify = Command::Identify(to_vec(&Identify::from(&config)).unwrap().into());
+
             // Send IDENTIFY
-            let identify = Frame::Message {message: request.clone(), body: false };
-            let ch = transport.send(identify)
-                .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
-                .and_then(|(resp, transport)| {
-                    Ok(transport)                      
-                });
-            ch
+            let transport = NsqCodec.framed(io);
+            send(transport, identify)
+                .and_then(read_response)
+                .and_then(move |(resp, transport)| {
+                    let features = negotiated(&config, &resp)?;
+                    Ok((transport, config, features))
+                })
+        })
+        .and_then(move |(transport, config, features)| {
+            let agreed = features.clone().unwrap_or_default();
+            let transport = match (config.tls_v1, agreed.tls_v1) {
+                (_, true) => upgrade_tls(transport, &config, &server_name),
+                (true, false) => {
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "nsqd did not agree to tls_v1");
+                    return Box::new(future::err(err.into())) as Handshake<_>;
+                }
+                (false, false) => Box::new(future::ok(boxed(transport))),
+            };
+            // Compression runs on top of TLS.
+            let transport = if agreed.snappy {
+                Box::new(transport.and_then(upgrade_snappy))
+            } else if agreed.deflate {
+                let level = deflate_level(&config, &agreed);
+                Box::new(transport.and_then(move |transport| upgrade_deflate(transport, level)))
+            } else {
+                transport
+            };
+
+            // AUTH goes over the upgraded connection, nsqd refuses it in
+            // plaintext when TLS is required.
+            let secret = if agreed.auth_required { config.auth_secret.clone() } else { None };
+            let transport = transport.and_then(move |transport| {
+                match secret {
+                    Some(secret) => authenticate(transport, secret),
+                    None => Box::new(future::ok((transport, None))),
+                }
+            });
+
+            Box::new(transport.and_then(move |(transport, identity)| {
+                let rdy = rdy.map(|rdy| rdy.register(agreed.max_rdy_count));
+                let transport = NsqTransport::new(transport, config.heartbeat_interval, &handle, events, rdy)?;
+                Ok((transport, Session { features, identity }))
+            }))
         });
-        
-        Box::new(handshake)
-    }
+
+    Box::new(handshake)
 }
 
-#[derive(PartialEq, Debug, Clone)]
-pub struct RequestMessage {
-    pub version: Option<String>, 
-    pub header: Option<String>,
-    pub body: Option<String>,
-    pub body_messages: Option<Vec<String>>,
+fn send<T>(transport: Framed<T, NsqCodec>, command: Command) -> Handshake<Framed<T, NsqCodec>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    Box::new(transport.send(command).map_err(NsqError::from))
 }
 
-impl RequestMessage {
-    pub fn new() -> RequestMessage {
-        RequestMessage {
-            version: None,
-            header: None,
-            body: None,
-            body_messages: None,
-        }
-    }
+// Reads the response to a handshake command, error frames fail the handshake.
+fn read_response<T>(transport: Framed<T, NsqCodec>) -> Handshake<(String, Framed<T, NsqCodec>)>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let resp = transport.into_future()
+        .map_err(|(err, _)| NsqError::from(err))
+        .and_then(|(resp, transport)| {
+            match resp {
+                Some(NsqFrame::Response(resp)) => Ok((resp, transport)),
+                Some(NsqFrame::Error(err)) => Err(err),
+                Some(NsqFrame::Message(_)) => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected message during handshake").into()),
+                None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed during handshake").into()),
+            }
+        });
 
-    pub fn set_protocol_version(&mut self, version: &str) {
-        self.version = Some(String::from(version));
-    }
+    Box::new(resp)
+}
 
-    pub fn create_pub_command(&mut self, topic: String, message: String) {
-        self.header = Some(format!("{} {}\n", commands::PUB, topic));
-        self.body = Some(message);
-    }
+// Runs TLS on the socket, then expects nsqd to send OK over it.
+fn upgrade_tls<T>(transport: Framed<T, NsqCodec>, config: &Config, server_name: &str) -> Handshake<BoxedFramed>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    // nsqd waits for the TLS handshake, nothing can be buffered yet.
+    let upgraded = tls::upgrade(transport.into_inner(), &config.tls, server_name)
+        .and_then(|io| read_ok(Box::new(io)));
+
+    Box::new(upgraded)
+}
+
+// nsqd may have sent its compressed OK along with the IDENTIFY response,
+// what was already read is decoded as part of the compressed stream.
+fn upgrade_snappy(transport: BoxedFramed) -> Handshake<BoxedFramed> {
+    let parts = transport.into_parts();
+    read_ok(Box::new(SnappyStream::new(parts.io, parts.read_buf)))
+}
+
+fn upgrade_deflate(transport: BoxedFramed, level: u32) -> Handshake<BoxedFramed> {
+    let parts = transport.into_parts();
+    read_ok(Box::new(DeflateStream::new(parts.io, level, parts.read_buf)))
+}
+
+// Sends the secret and parses the identity nsqd's auth server returned.
+// A rejected secret fails with E_AUTH_FAILED or E_UNAUTHORIZED.
+fn authenticate(transport: BoxedFramed, secret: String) -> Handshake<(BoxedFramed, Option<AuthIdentity>)> {
+    let authenticated = send(transport, Command::Auth(secret.into()))
+        .and_then(read_response)
+        .and_then(|(resp, transport)| {
+            let identity: AuthIdentity = from_str(&resp)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            Ok((transport, Some(identity)))
+        });
+
+    Box::new(authenticated)
+}
 
-    pub fn create_mpub_command(&mut self, topic: String, messages: Vec<String>) {
-        self.header = Some(format!("{} {}\n", commands::MPUB, topic));
-        self.body_messages = Some(messages);
+// The level asked for, as nsqd clamps it to its max_deflate_level.
+fn deflate_level(config: &Config, agreed: &NegotiatedFeatures) -> u32 {
+    let level = match config.deflate_level {
+        0 => DEFAULT_DEFLATE_LEVEL,
+        level => level,
+    };
+    let level = match agreed.max_deflate_level {
+        0 => level,
+        max => cmp::min(level, max),
+    };
+    cmp::min(u32::from(level), 9)
+}
+
+// Frames an upgraded connection and reads the OK confirming the upgrade.
+fn read_ok(io: Box<dyn Io>) -> Handshake<BoxedFramed> {
+    let upgraded = read_response(NsqCodec.framed(io))
+        .and_then(|(resp, transport)| expect_ok(&resp).map(|_| transport));
+
+    Box::new(upgraded)
+}
+
+fn expect_ok(resp: &str) -> Result<(), NsqError> {
+    if resp != "OK" {
+        let err = io::Error::new(io::ErrorKind::InvalidData, format!("expected OK, got {}", resp));
+        return Err(err.into());
     }
+    Ok(())
+}
 
-    pub fn create_dpub_command(&mut self, topic: String, message: String, defer_time: i64) {
-        self.header = Some(format!("{} {} {}\n", commands::DPUB, topic, defer_time.to_string()));
-        self.body = Some(message);
+// Erases the connection type, keeping anything already buffered.
+fn boxed<T: AsyncRead + AsyncWrite + 'static>(transport: Framed<T, NsqCodec>) -> BoxedFramed {
+    let parts = transport.into_parts();
+    let mut boxed = FramedParts::new(Box::new(parts.io) as Box<dyn Io>, parts.codec);
+    boxed.read_buf = parts.read_buf;
+    boxed.write_buf = parts.write_buf;
+    Framed::from_parts(boxed)
+}
+
+// nsqd answers IDENTIFY with JSON when feature negotiation was requested,
+// and with a plain OK otherwise.
+fn negotiated(config: &Config, resp: &str) -> Result<Option<NegotiatedFeatures>, NsqError> {
+    if !config.feature_negotiation || resp == "OK" {
+        return Ok(None);
     }
 
-    pub fn create_sub_command(&mut self, topic: String, channel: String) {
-        self.header = Some(format!("{} {} {}\n", commands::SUB, topic, channel));
+    let features: NegotiatedFeatures = from_str(resp)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if features.auth_required && config.auth_secret.is_none() {
+        return Err(NsqError::AuthRequired);
     }
+    Ok(Some(features))
+}
 
-    pub fn create_identify_command(&mut self, config: Config) {
-        self.header = Some(format!("{}\n", commands::IDENTIFY));
-        // Serialize it to a JSON string.
-        self.body = Some(to_string(&config).unwrap());
-    }  
 
-    pub fn create_rdy_command(&mut self) {
-        self.header = Some(format!("{} 1\n", commands::RDY));
-    }  
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn create_fin_command(&mut self, message_id: String) {
-        self.header = Some(format!("{} {}\n", commands::FIN, message_id));
-    } 
+    #[test]
+    fn deflate_level_is_clamped_to_what_nsqd_allows() {
+        let agreed = |max| NegotiatedFeatures { deflate: true, max_deflate_level: max, ..NegotiatedFeatures::default() };
 
-    pub fn create_nop_command(&mut self) {
-        self.header = Some(format!("{}\n", commands::NOP));
-    }           
-}
\ No newline at end of file
+        assert_eq!(deflate_level(&Config::default().deflate(9), &agreed(3)), 3);
+        assert_eq!(deflate_level(&Config::default().deflate(2), &agreed(6)), 2);
+        assert_eq!(deflate_level(&Config::default().deflate(0), &agreed(9)), 6);
+        assert_eq!(deflate_level(&Config::default().deflate(12), &agreed(0)), 9);
+    }
+}
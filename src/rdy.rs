@@ -0,0 +1,611 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Interval, Timeout};
+
+use backoff::{self, BackoffStrategy, ExponentialBackoff};
+use config::Config;
+
+/// RDY counts of the connections of a consumer, kept so that at most
+/// max_in_flight messages are in flight over all of them.
+///
+/// Each connection gets an equal share. With more connections than
+/// max_in_flight, some get RDY 1 while the others wait, and connections
+/// that stayed idle make way for waiting ones from time to time.
+///
+/// Failures put the consumer in backoff: RDY drops to 0 for an interval,
+/// after which a single connection tests with RDY 1. Every success takes
+/// back one failure, full RDY returns once they are all made up for.
+#[derive(Clone)]
+pub(crate) struct RdyControl {
+    inner: Rc<RefCell<State>>,
+}
+
+/// The part of a connection in `RdyControl`, kept by its transport and
+/// given up when dropped.
+pub(crate) struct ConnectionRdy {
+    id: usize,
+    control: RdyControl,
+}
+
+struct State {
+    max_in_flight: u64,
+    // The consumer stopped, no connection gets RDY anymore.
+    stopped: bool,
+    low_rdy_idle_timeout: Duration,
+    next_id: usize,
+    connections: BTreeMap<usize, Connection>,
+    backoff: Backoff,
+    handle: Handle,
+}
+
+struct Backoff {
+    strategy: Box<dyn BackoffStrategy>,
+    multiplier: Duration,
+    max: Duration,
+    // Failures not made up for yet, backing off while above 0.
+    counter: u32,
+    // Waiting an interval out, results are ignored until it passed.
+    waiting: bool,
+    // Connection testing with RDY 1, and the one that did last.
+    testing: Option<usize>,
+    last_tested: Option<usize>,
+}
+
+struct Connection {
+    // nsqd refuses RDY before SUB.
+    subscribed: bool,
+    // Highest count nsqd accepts, 0 when unknown.
+    max_rdy_count: u64,
+    // Last count sent, and the one the connection should have.
+    rdy: u64,
+    target: u64,
+    // Highest count nsqd may still go by, the one sent before `rdy` when
+    // that lowered it. nsqd never has more messages in flight.
+    ceiling: u64,
+    in_flight: u64,
+    // Last message received, or when RDY was given after waiting.
+    last_active: Instant,
+    waiting_since: Instant,
+    // Count the transport still has to send, and its task to wake for it.
+    update: Option<u64>,
+    task: Option<Task>,
+}
+
+impl RdyControl {
+    /// Also reconsiders which connections get RDY every
+    /// `Config::rdy_redistribute_interval`, for as long as the consumer
+    /// exists.
+    pub fn new(config: &Config, handle: &Handle) -> RdyControl {
+        let state = State {
+            max_in_flight: config.max_in_flight,
+            stopped: false,
+            low_rdy_idle_timeout: Duration::from_millis(config.low_rdy_idle_timeout),
+            next_id: 0,
+            connections: BTreeMap::new(),
+            backoff: Backoff {
+                strategy: Box::new(ExponentialBackoff),
+                multiplier: Duration::from_millis(config.backoff_multiplier),
+                max: Duration::from_millis(config.max_backoff_duration),
+                counter: 0,
+                waiting: false,
+                testing: None,
+                last_tested: None,
+            },
+            handle: handle.clone(),
+        };
+        let control = RdyControl { inner: Rc::new(RefCell::new(state)) };
+
+        if config.rdy_redistribute_interval > 0 {
+            control.rotate_every(Duration::from_millis(config.rdy_redistribute_interval), handle);
+        }
+        control
+    }
+
+    fn rotate_every(&self, interval: Duration, handle: &Handle) {
+        let interval = match Interval::new(interval, handle) {
+            Ok(interval) => interval,
+            Err(_) => return,
+        };
+        let state: Weak<RefCell<State>> = Rc::downgrade(&self.inner);
+        let rotation = interval
+            .map_err(|_| ())
+            .take_while(move |_| {
+                Ok(match state.upgrade() {
+                    Some(state) => {
+                        state.borrow_mut().rebalance(true);
+                        true
+                    }
+                    None => false,
+                })
+            })
+            .for_each(|_| Ok(()));
+        handle.spawn(rotation);
+    }
+
+    pub fn register(&self, max_rdy_count: u64) -> ConnectionRdy {
+        let mut state = self.inner.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let now = Instant::now();
+        state.connections.insert(id, Connection {
+            subscribed: false,
+            max_rdy_count,
+            rdy: 0,
+            target: 0,
+            ceiling: 0,
+            in_flight: 0,
+            last_active: now,
+            waiting_since: now,
+            update: None,
+            task: None,
+        });
+        ConnectionRdy { id, control: self.clone() }
+    }
+
+    pub fn set_max_in_flight(&self, max_in_flight: u64) {
+        let mut state = self.inner.borrow_mut();
+        state.max_in_flight = max_in_flight;
+        state.rebalance(false);
+    }
+
+    /// Takes RDY from every connection for good. The consumer sends the
+    /// RDY 0 itself, ahead of CLS.
+    pub fn stop(&self) {
+        let mut state = self.inner.borrow_mut();
+        state.stopped = true;
+        for conn in state.connections.values_mut() {
+            conn.target = 0;
+            conn.rdy = 0;
+            conn.update = None;
+        }
+    }
+
+    pub fn set_backoff_strategy(&self, strategy: Box<dyn BackoffStrategy>) {
+        self.inner.borrow_mut().backoff.strategy = strategy;
+    }
+
+    /// A message was handled, which takes back one failure.
+    pub fn succeeded(&self) {
+        self.record(true);
+    }
+
+    /// A message failed and was requeued, which starts or prolongs backoff.
+    pub fn failed(&self) {
+        self.record(false);
+    }
+
+    fn record(&self, success: bool) {
+        let mut state = self.inner.borrow_mut();
+        if !state.backoff.record(success) {
+            return;
+        }
+
+        if state.backoff.counter > 0 {
+            let interval = state.backoff.interval(state.backoff.counter);
+            state.backoff.waiting = true;
+            state.backoff.testing = None;
+
+            let weak = Rc::downgrade(&self.inner);
+            match Timeout::new(interval, &state.handle) {
+                Ok(timeout) => state.handle.spawn(timeout.then(move |_| {
+                    if let Some(state) = weak.upgrade() {
+                        let mut state = state.borrow_mut();
+                        state.backoff.waiting = false;
+                        state.rebalance(false);
+                    }
+                    Ok(())
+                })),
+                Err(_) => state.backoff.waiting = false,
+            }
+        }
+        state.rebalance(false);
+    }
+}
+
+impl ConnectionRdy {
+    /// Called as the first RDY is sent, returns the count it should have.
+    pub fn subscribed(&self) -> u64 {
+        self.with(|state| {
+            state.connections.get_mut(&self.id).unwrap().subscribed = true;
+            state.rebalance(false);
+            // Sent by the caller, not as an update.
+            let conn = state.connections.get_mut(&self.id).unwrap();
+            conn.update = None;
+            conn.rdy
+        })
+    }
+
+    /// A message arrived. Beyond the RDY count nsqd goes by, it is one
+    /// delivered again after timing out, only one FIN of which succeeds.
+    pub fn received(&self) {
+        self.with(|state| {
+            let conn = state.connections.get_mut(&self.id).unwrap();
+            if conn.in_flight < conn.ceiling {
+                conn.in_flight += 1;
+            }
+            conn.last_active = Instant::now();
+        });
+    }
+
+    /// A message was finished or requeued, which may leave room for
+    /// other connections.
+    pub fn finished(&self) {
+        self.with(|state| {
+            {
+                let conn = state.connections.get_mut(&self.id).unwrap();
+                conn.in_flight = conn.in_flight.saturating_sub(1);
+            }
+            state.rebalance(false);
+        });
+    }
+
+    /// Takes the RDY count to send, the current task is woken when there
+    /// is a new one.
+    pub fn poll_update(&self) -> Option<u64> {
+        self.with(|state| {
+            let conn = state.connections.get_mut(&self.id).unwrap();
+            conn.task = Some(task::current());
+            conn.update.take()
+        })
+    }
+
+    /// Puts back an update the connection could not take yet.
+    pub fn retry(&self, count: u64) {
+        self.with(|state| {
+            let conn = state.connections.get_mut(&self.id).unwrap();
+            conn.update.get_or_insert(count);
+        });
+    }
+
+    fn with<F: FnOnce(&mut State) -> R, R>(&self, f: F) -> R {
+        f(&mut self.control.inner.borrow_mut())
+    }
+}
+
+impl Drop for ConnectionRdy {
+    fn drop(&mut self) {
+        let mut state = self.control.inner.borrow_mut();
+        state.connections.remove(&self.id);
+        state.rebalance(false);
+    }
+}
+
+impl State {
+    fn rebalance(&mut self, rotate: bool) {
+        self.plan(rotate);
+        self.apply();
+    }
+
+    // Decides the count each subscribed connection should have.
+    fn plan(&mut self, rotate: bool) {
+        if self.stopped {
+            for conn in self.connections.values_mut() {
+                conn.target = 0;
+            }
+            return;
+        }
+        if self.backoff.counter > 0 {
+            self.plan_backoff();
+            return;
+        }
+
+        let now = Instant::now();
+        let max_in_flight = self.max_in_flight;
+        let idle_timeout = self.low_rdy_idle_timeout;
+        let mut connections: Vec<&mut Connection> = self.connections.values_mut()
+            .filter(|conn| conn.subscribed)
+            .collect();
+
+        let count = connections.len() as u64;
+        if count == 0 {
+            return;
+        }
+        if count <= max_in_flight {
+            for conn in connections {
+                conn.target = conn.capped(max_in_flight / count);
+            }
+            return;
+        }
+
+        // Some connections get RDY 1, the others wait for one to be idle.
+        let mut active = 0;
+        for conn in &mut connections {
+            let idle = now.duration_since(conn.last_active) >= idle_timeout;
+            if conn.target > 0 && (active == max_in_flight || rotate && idle) {
+                conn.target = 0;
+                conn.waiting_since = now;
+            }
+            if conn.target > 0 {
+                conn.target = 1;
+                active += 1;
+            }
+        }
+
+        connections.sort_by_key(|conn| conn.waiting_since);
+        let waiting = connections.into_iter().filter(|conn| conn.target == 0);
+        for conn in waiting.take((max_in_flight - active) as usize) {
+            conn.target = 1;
+            conn.last_active = now;
+        }
+    }
+
+    // Once an interval passed, connections take turns testing with RDY 1.
+    fn plan_backoff(&mut self) {
+        let testing = self.backoff.testing.and_then(|id| self.connections.get(&id));
+        if !self.backoff.waiting && !testing.is_some_and(|conn| conn.subscribed) {
+            let last = self.backoff.last_tested;
+            let mut subscribed = self.connections.iter()
+                .filter(|&(_, conn)| conn.subscribed)
+                .map(|(&id, _)| id);
+            let next = subscribed.clone().find(|&id| Some(id) > last).or_else(|| subscribed.next());
+            self.backoff.testing = next;
+            self.backoff.last_tested = next.or(last);
+        }
+
+        for (&id, conn) in self.connections.iter_mut() {
+            conn.target = if Some(id) == self.backoff.testing { 1 } else { 0 };
+        }
+    }
+
+    // Sends the planned counts. Counts are lowered right away, raised only
+    // as far as the messages still in flight elsewhere allow.
+    fn apply(&mut self) {
+        let max_in_flight = self.max_in_flight;
+        for conn in self.connections.values_mut() {
+            if conn.subscribed && conn.target < conn.rdy {
+                let target = conn.target;
+                conn.send(target);
+            }
+        }
+
+        let mut used: u64 = self.connections.values().map(Connection::used).sum();
+        for conn in self.connections.values_mut() {
+            if conn.subscribed && conn.target > conn.rdy {
+                let others = used - conn.used();
+                let count = cmp::min(conn.target, max_in_flight.saturating_sub(others));
+                if count > conn.rdy {
+                    conn.send(count);
+                    used = others + conn.used();
+                }
+            }
+        }
+    }
+}
+
+impl Backoff {
+    // Counts a result, returns whether backoff starts, goes on or ends.
+    fn record(&mut self, success: bool) -> bool {
+        if self.waiting {
+            return false;
+        }
+        if success {
+            if self.counter == 0 {
+                return false;
+            }
+            self.counter -= 1;
+            true
+        } else {
+            // Failures stop counting once `multiplier * 2^attempt` would
+            // exceed the maximum. Not the strategy's interval, it is random.
+            let next = backoff::exponential(self.counter + 1, self.multiplier);
+            if self.max > Duration::from_millis(0) && next <= self.max {
+                self.counter += 1;
+            }
+            self.counter > 0
+        }
+    }
+
+    fn interval(&self, attempt: u32) -> Duration {
+        cmp::min(self.strategy.calculate(attempt, self.multiplier), self.max)
+    }
+}
+
+impl Connection {
+    fn capped(&self, count: u64) -> u64 {
+        if self.max_rdy_count > 0 {
+            cmp::min(count, self.max_rdy_count)
+        } else {
+            count
+        }
+    }
+
+    // Messages this connection may have in flight.
+    fn used(&self) -> u64 {
+        cmp::max(self.rdy, self.in_flight)
+    }
+
+    fn send(&mut self, count: u64) {
+        self.ceiling = cmp::max(count, self.rdy);
+        self.rdy = count;
+        self.update = Some(count);
+        if let Some(ref task) = self.task {
+            task.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backoff::ConstantBackoff;
+    use tokio_core::reactor::Core;
+
+    thread_local!(static CORE: RefCell<Core> = RefCell::new(Core::new().unwrap()));
+
+    fn control(max_in_flight: u64) -> RdyControl {
+        control_with(Config { max_in_flight, ..Config::default() })
+    }
+
+    fn control_with(config: Config) -> RdyControl {
+        let config = Config { low_rdy_idle_timeout: 0, rdy_redistribute_interval: 0, ..config };
+        CORE.with(|core| RdyControl::new(&config, &core.borrow().handle()))
+    }
+
+    fn wait(ms: u64) {
+        CORE.with(|core| {
+            let mut core = core.borrow_mut();
+            let timeout = Timeout::new(Duration::from_millis(ms), &core.handle()).unwrap();
+            core.run(timeout).unwrap();
+        });
+    }
+
+    fn updates(conns: &[&ConnectionRdy]) -> Vec<Option<u64>> {
+        conns.iter().map(|conn| conn.with(|state| state.connections.get_mut(&conn.id).unwrap().update.take())).collect()
+    }
+
+    fn rdy(conn: &ConnectionRdy) -> u64 {
+        conn.with(|state| state.connections[&conn.id].rdy)
+    }
+
+    fn in_flight(conn: &ConnectionRdy) -> u64 {
+        conn.with(|state| state.connections[&conn.id].in_flight)
+    }
+
+    #[test]
+    fn single_connection_gets_max_in_flight() {
+        let control = control(100);
+        let conn = control.register(0);
+        assert_eq!(conn.subscribed(), 100);
+
+        let capped = control.register(25);
+        assert_eq!(capped.subscribed(), 25);
+        assert_eq!(updates(&[&conn]), vec![Some(50)]);
+    }
+
+    #[test]
+    fn raises_wait_for_messages_in_flight() {
+        let control = control(10);
+        let a = control.register(0);
+        assert_eq!(a.subscribed(), 10);
+        for _ in 0..10 {
+            a.received();
+        }
+
+        // Lowered right away, b only gets what a does not have in flight.
+        let b = control.register(0);
+        assert_eq!(b.subscribed(), 0);
+        assert_eq!(updates(&[&a]), vec![Some(5)]);
+
+        for _ in 0..3 {
+            a.finished();
+        }
+        assert_eq!(rdy(&b), 3);
+        a.finished();
+        a.finished();
+        assert_eq!(updates(&[&a, &b]), vec![None, Some(5)]);
+    }
+
+    #[test]
+    fn redeliveries_are_not_counted_twice() {
+        let control = control(1);
+        let a = control.register(0);
+        assert_eq!(a.subscribed(), 1);
+
+        // Timed out on nsqd and delivered again, only one FIN succeeds.
+        a.received();
+        a.received();
+        assert_eq!(in_flight(&a), 1);
+        a.finished();
+        assert_eq!(in_flight(&a), 0);
+    }
+
+    #[test]
+    fn more_connections_than_max_in_flight_take_turns() {
+        let control = control(2);
+        let mut conns: Vec<_> = (0..3).map(|_| control.register(0)).collect();
+        let counts: Vec<_> = conns.iter().map(ConnectionRdy::subscribed).collect();
+        assert_eq!(counts, vec![2, 1, 0]);
+        assert_eq!(updates(&[&conns[0], &conns[1], &conns[2]]), vec![Some(1), None, None]);
+
+        // Every connection is idle, the one waiting longest goes first.
+        control.inner.borrow_mut().rebalance(true);
+        assert_eq!(updates(&[&conns[0], &conns[1], &conns[2]]), vec![None, Some(0), Some(1)]);
+
+        drop(conns.remove(0));
+        assert_eq!(control.inner.borrow().connections.values().map(|conn| conn.rdy).collect::<Vec<_>>(), vec![1, 1]);
+    }
+
+    #[test]
+    fn max_in_flight_changes_apply_to_every_connection() {
+        let control = control(4);
+        let a = control.register(0);
+        let b = control.register(0);
+        a.subscribed();
+        b.subscribed();
+        updates(&[&a, &b]);
+
+        control.set_max_in_flight(8);
+        assert_eq!(updates(&[&a, &b]), vec![Some(4), Some(4)]);
+        control.set_max_in_flight(0);
+        assert_eq!(updates(&[&a, &b]), vec![Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn stopped_consumers_give_out_no_rdy() {
+        let control = control(4);
+        let a = control.register(0);
+        assert_eq!(a.subscribed(), 4);
+        a.received();
+
+        control.stop();
+        a.finished();
+        control.set_max_in_flight(8);
+        let b = control.register(0);
+        assert_eq!(b.subscribed(), 0);
+        assert_eq!(updates(&[&a, &b]), vec![None, None]);
+        assert_eq!(rdy(&a), 0);
+    }
+
+    #[test]
+    fn failures_back_off_until_made_up_for() {
+        let control = control_with(Config { max_in_flight: 10, backoff_multiplier: 20, ..Config::default() });
+        control.set_backoff_strategy(Box::new(ConstantBackoff));
+        let a = control.register(0);
+        let b = control.register(0);
+        a.subscribed();
+        b.subscribed();
+        updates(&[&a, &b]);
+
+        control.failed();
+        control.failed();
+        assert_eq!(updates(&[&a, &b]), vec![Some(0), Some(0)]);
+        control.failed();
+        assert_eq!(control.inner.borrow().backoff.counter, 1);
+
+        // One connection at a time tests once the interval passed.
+        wait(100);
+        assert_eq!(updates(&[&a, &b]), vec![Some(1), None]);
+        control.failed();
+        wait(100);
+        assert_eq!(updates(&[&a, &b]), vec![Some(0), Some(1)]);
+
+        control.succeeded();
+        assert_eq!(updates(&[&a, &b]), vec![None, Some(0)]);
+        wait(100);
+        control.succeeded();
+        assert_eq!(updates(&[&a, &b]), vec![Some(5), Some(5)]);
+    }
+
+    #[test]
+    fn backoff_stops_growing_at_the_max() {
+        let control = control_with(Config { backoff_multiplier: 1_000, max_backoff_duration: 10_000, ..Config::default() });
+        control.set_backoff_strategy(Box::new(::backoff::FullJitterBackoff));
+        for _ in 0..10 {
+            control.inner.borrow_mut().backoff.waiting = false;
+            control.failed();
+        }
+        // 2s, 4s and 8s, 16s would exceed 10s, whatever the jitter.
+        assert_eq!(control.inner.borrow().backoff.counter, 3);
+
+        let disabled = control_with(Config { max_backoff_duration: 0, ..Config::default() });
+        disabled.failed();
+        assert_eq!(disabled.inner.borrow().backoff.counter, 0);
+    }
+}
@@ -1,33 +1,154 @@
-use std::io;
-use futures::{Stream, Poll, Async};
-use tokio_proto::streaming::Body;
+use std::fmt;
+use std::error;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::str::{self, FromStr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use futures::{Future, Stream, Poll, Async};
+use futures::sync::mpsc::UnboundedReceiver;
 
-#[derive(Debug)]
+use config::DropAction;
+use consumer::{Connections, Consumer};
+use error::NsqError;
+
+/// Stream of messages received after subscribing, from every connection
+/// of the consumer.
+///
+/// Error frames sent by nsqd are yielded as errors. After a non-fatal one
+/// (see `NsqError::is_fatal`) the stream can keep being polled.
 pub struct ResponseStream {
-    pub inner: Body<Message, io::Error>,
+    pub(crate) inner: UnboundedReceiver<Result<Delivery, NsqError>>,
+    // Keeps the connections open while the messages are read.
+    pub(crate) _connections: Connections,
 }
 
-impl Stream for ResponseStream {          
-    type Item = Message;
-    type Error = io::Error;
+impl Stream for ResponseStream {
+    type Item = Delivery;
+    type Error = NsqError;
 
-    fn poll(&mut self) -> Poll<Option<Message>, io::Error> {
-        match self.inner.poll().unwrap() {
-            Async::Ready(Some(request)) => {
-                Ok(Async::Ready(Some(request)))
-            }
-            Async::Ready(None) => {
-                // the stream finished.
-                Ok(Async::Ready(None))
-            }
-            Async::NotReady =>  {
-                // no more messages to read
-                Ok(Async::NotReady)
+    fn poll(&mut self) -> Poll<Option<Delivery>, NsqError> {
+        match self.inner.poll() {
+            Ok(Async::Ready(Some(Ok(delivery)))) => Ok(Async::Ready(Some(delivery))),
+            Ok(Async::Ready(Some(Err(err)))) => Err(err),
+            // Every connection and the consumer are gone.
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A message along with the connection it came from, to acknowledge it on.
+///
+/// `finish` and `requeue` take the delivery, so it can only be acknowledged
+/// once. Dropping it unacknowledged does what `Config::on_drop` says,
+/// requeueing it by default.
+pub struct Delivery {
+    message: Message,
+    origin: SocketAddr,
+    consumer: Consumer,
+}
+
+impl Delivery {
+    pub(crate) fn new(message: Message, origin: SocketAddr, consumer: Consumer) -> Delivery {
+        Delivery { message, origin, consumer }
+    }
+
+    /// Address of the nsqd the message came from, which acknowledgements
+    /// are sent to.
+    pub fn origin(&self) -> SocketAddr {
+        self.origin
+    }
+
+    /// See `Consumer::fin`.
+    pub fn finish(self) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.consumer.fin_from(Some(self.origin), self.message.message_id)
+    }
+
+    /// See `Consumer::requeue`.
+    pub fn requeue(self, delay: Duration) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.consumer.requeue_from(Some(self.origin), self.message.message_id, delay, true)
+    }
+
+    /// See `Consumer::requeue_without_backoff`.
+    pub fn requeue_without_backoff(self, delay: Duration) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.consumer.requeue_from(Some(self.origin), self.message.message_id, delay, false)
+    }
+
+    /// See `Consumer::touch`.
+    pub fn touch(&self) -> Box<dyn Future<Item = (), Error = NsqError>> {
+        self.consumer.touch_from(Some(self.origin), self.message.message_id)
+    }
+}
+
+impl Deref for Delivery {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        &self.message
+    }
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Delivery").field(&self.message).finish()
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        // Acknowledged through the consumer already, or connection gone.
+        let id = self.message.message_id;
+        if !self.consumer.is_unacked(self.origin, id) {
+            return;
+        }
+        // Nobody waits for these, nsqd does not answer them.
+        match self.consumer.config().on_drop {
+            DropAction::Requeue => {
+                let delay = self.consumer.requeue_delay(self.message.attempts);
+                drop(self.consumer.requeue_from(Some(self.origin), id, delay, true));
             }
-        }        
+            DropAction::Finish => drop(self.consumer.fin_from(Some(self.origin), id)),
+            DropAction::Ignore => self.consumer.ignored(self.origin, id),
+        }
     }
 }
 
+/// Features nsqd agreed to in its IDENTIFY reply.
+///
+/// Durations are in milliseconds. Fields missing from the reply, as with
+/// older nsqd versions, keep their default value.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct NegotiatedFeatures {
+    pub version: String,
+    /// Highest RDY count nsqd accepts on this connection.
+    pub max_rdy_count: u64,
+    pub msg_timeout: u64,
+    /// Highest timeout a REQ or TOUCH may ask for.
+    pub max_msg_timeout: u64,
+    pub tls_v1: bool,
+    pub deflate: bool,
+    pub deflate_level: u16,
+    pub max_deflate_level: u16,
+    pub snappy: bool,
+    pub sample_rate: u16,
+    /// Whether AUTH must be sent before any other command.
+    pub auth_required: bool,
+    pub output_buffer_size: i64,
+    pub output_buffer_timeout: i64,
+}
+
+/// Identity nsqd's auth server reported for the AUTH secret.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AuthIdentity {
+    pub identity: String,
+    pub identity_url: String,
+    /// Number of topic and channel permissions the secret grants.
+    pub permission_count: u64,
+}
+
 /*
 impl<T> Sink for ResponseStream<T>
     where T: Sink<SinkItem = String, SinkError = io::Error>,
@@ -45,8 +166,153 @@ impl<T> Sink for ResponseStream<T>
 }
 */
 
+#[derive(Clone, Debug)]
 pub struct Message {
-    pub timestamp: i64,
-    pub message_id: String,
-    pub message_body: String
-}
\ No newline at end of file
+    pub timestamp: SystemTime,
+    pub attempts: u16,
+    pub message_id: MessageId,
+    pub message_body: Bytes,
+}
+
+impl Message {
+    /// Builds a message from the raw nanosecond timestamp sent by nsqd.
+    pub fn new(timestamp: i64, attempts: u16, message_id: MessageId, message_body: Bytes) -> Message {
+        Message {
+            timestamp: UNIX_EPOCH + Duration::from_nanos(timestamp.max(0) as u64),
+            attempts,
+            message_id,
+            message_body,
+        }
+    }
+}
+
+/// The 16-byte identifier nsqd assigns to every message, the raw bytes are
+/// what goes on the wire for FIN, REQ and TOUCH.
+///
+/// nsqd's ids are 16 ASCII hex characters already, and are displayed as
+/// such, the way nsqd logs them and nsqadmin shows them. Any other bytes
+/// are displayed as 32 hex characters. Both forms parse back.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct MessageId(pub [u8; 16]);
+
+impl MessageId {
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl From<[u8; 16]> for MessageId {
+    fn from(bytes: [u8; 16]) -> MessageId {
+        MessageId(bytes)
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.iter().all(u8::is_ascii_hexdigit) {
+            // Only ASCII, so valid UTF-8.
+            return f.write_str(str::from_utf8(&self.0).unwrap_or_default());
+        }
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MessageId({})", self)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = ParseMessageIdError;
+
+    fn from_str(s: &str) -> Result<MessageId, ParseMessageIdError> {
+        let hex = s.as_bytes();
+        // from_str_radix would take a sign as well.
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return Err(ParseMessageIdError(()));
+        }
+
+        let mut id = [0u8; 16];
+        // As nsqd displays it.
+        if hex.len() == 16 {
+            id.copy_from_slice(hex);
+            return Ok(MessageId(id));
+        }
+        if hex.len() != 32 {
+            return Err(ParseMessageIdError(()));
+        }
+
+        for (byte, pair) in id.iter_mut().zip(hex.chunks(2)) {
+            let pair = str::from_utf8(pair).map_err(|_| ParseMessageIdError(()))?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| ParseMessageIdError(()))?;
+        }
+
+        Ok(MessageId(id))
+    }
+}
+
+/// Error returned when parsing a `MessageId` from a string that is not
+/// 16 or 32 hex characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseMessageIdError(());
+
+impl fmt::Display for ParseMessageIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "message id must be 16 or 32 hex characters")
+    }
+}
+
+impl error::Error for ParseMessageIdError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_ids_are_displayed_as_nsqd_does() {
+        let id = MessageId(*b"0a1b2c3d4e5f6789");
+        assert_eq!(id.to_string(), "0a1b2c3d4e5f6789");
+        assert_eq!("0a1b2c3d4e5f6789".parse::<MessageId>().unwrap(), id);
+    }
+
+    #[test]
+    fn message_ids_round_trip_through_hex() {
+        let id = MessageId([0x00, 0x01, 0x7f, 0x80, 0xab, 0xcd, 0xef, 0xff, 0x10, 0x32, 0x54, 0x76, 0x98, 0xba, 0xdc, 0xfe]);
+        let text = id.to_string();
+        assert_eq!(text, "00017f80abcdefff1032547698badcfe");
+        assert_eq!(text.parse::<MessageId>().unwrap(), id);
+        assert_eq!("00017F80ABCDEFFF1032547698BADCFE".parse::<MessageId>().unwrap(), id);
+    }
+
+    #[test]
+    fn message_ids_must_be_16_or_32_hex_characters() {
+        let rejected = [
+            "",
+            "0a1b2c3d4e5f678",
+            "0a1b2c3d4e5f6789a",
+            "+0+0+0+0+0+0+0+0",
+            "00017f80abcdefff1032547698badcf",
+            "00017f80abcdefff1032547698badcfe0",
+            "00017f80abcdefff1032547698badcfg",
+            "+0+0+0+0+0+0+0+0+0+0+0+0+0+0+0+0",
+            // 32 bytes, the last two of them not ASCII.
+            "00017f80abcdefff1032547698badcé",
+        ];
+        for text in &rejected {
+            assert_eq!(text.parse::<MessageId>(), Err(ParseMessageIdError(())), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn timestamps_are_nanoseconds_since_the_epoch() {
+        let message = Message::new(1_500_000_000_123_456_789, 1, MessageId::default(), Bytes::new());
+        assert_eq!(message.timestamp, UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_789));
+
+        let before_epoch = Message::new(-1, 1, MessageId::default(), Bytes::new());
+        assert_eq!(before_epoch.timestamp, UNIX_EPOCH);
+    }
+}
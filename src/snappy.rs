@@ -0,0 +1,277 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use bytes::{BufMut, BytesMut};
+use crc32c::crc32c;
+use futures::{Async, Poll};
+use snap::raw::{decompress_len, max_compress_len, Decoder, Encoder};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use compression::corrupt;
+
+// Sent once before any other chunk.
+const STREAM_IDENTIFIER: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+const CHUNK_HEADER_LENGTH: usize = 4;
+const CHECKSUM_LENGTH: usize = 4;
+// Most uncompressed data a single chunk may hold.
+const MAX_BLOCK_SIZE: usize = 65536;
+
+const COMPRESSED: u8 = 0x00;
+const UNCOMPRESSED: u8 = 0x01;
+const IDENTIFIER: u8 = 0xff;
+
+/// A connection speaking the snappy framing format, which nsqd switches to
+/// once IDENTIFY agreed to snappy.
+///
+/// Chunks are only decoded once fully received, so the stream can be read
+/// without blocking. Every write becomes one chunk, sent on flush.
+pub struct SnappyStream<T> {
+    inner: T,
+    decoder: Decoder,
+    encoder: Encoder,
+    // Compressed data received but not decoded yet.
+    read_buf: BytesMut,
+    // Decoded data not handed to the reader yet.
+    plain: BytesMut,
+    // Chunks not written to the connection yet.
+    write_buf: BytesMut,
+    scratch: Vec<u8>,
+}
+
+impl<T> SnappyStream<T> {
+    /// `buffered` is data already read from the connection, it belongs to
+    /// the compressed stream.
+    pub fn new(inner: T, buffered: BytesMut) -> SnappyStream<T> {
+        SnappyStream {
+            inner,
+            decoder: Decoder::new(),
+            encoder: Encoder::new(),
+            read_buf: buffered,
+            plain: BytesMut::new(),
+            write_buf: BytesMut::from(STREAM_IDENTIFIER),
+            scratch: Vec::new(),
+        }
+    }
+
+    // Decodes the next chunk if it was fully received.
+    fn decode_chunk(&mut self) -> io::Result<bool> {
+        if self.read_buf.len() < CHUNK_HEADER_LENGTH {
+            return Ok(false);
+        }
+        let len = self.read_buf[1] as usize | (self.read_buf[2] as usize) << 8 | (self.read_buf[3] as usize) << 16;
+        if self.read_buf.len() < CHUNK_HEADER_LENGTH + len {
+            return Ok(false);
+        }
+
+        let chunk = self.read_buf.split_to(CHUNK_HEADER_LENGTH + len);
+        let data = &chunk[CHUNK_HEADER_LENGTH..];
+        match chunk[0] {
+            kind @ COMPRESSED | kind @ UNCOMPRESSED => {
+                if data.len() < CHECKSUM_LENGTH {
+                    return Err(corrupt("chunk too short for its checksum"));
+                }
+                let checksum = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                let data = &data[CHECKSUM_LENGTH..];
+
+                let start = self.plain.len();
+                if kind == COMPRESSED {
+                    let len = decompress_len(data).map_err(corrupt)?;
+                    if len > MAX_BLOCK_SIZE {
+                        return Err(corrupt("chunk larger than the block size"));
+                    }
+                    self.plain.resize(start + len, 0);
+                    self.decoder.decompress(data, &mut self.plain[start..]).map_err(corrupt)?;
+                } else {
+                    self.plain.extend_from_slice(data);
+                }
+
+                if masked_crc(&self.plain[start..]) != checksum {
+                    return Err(corrupt("checksum mismatch"));
+                }
+            }
+            IDENTIFIER if data != b"sNaPpY" => return Err(corrupt("invalid stream identifier")),
+            0x02..=0x7f => return Err(corrupt("reserved unskippable chunk")),
+            // Padding and skippable chunks.
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn encode_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        let checksum = masked_crc(data);
+
+        self.scratch.resize(max_compress_len(data.len()), 0);
+        let len = self.encoder.compress(data, &mut self.scratch).map_err(corrupt)?;
+        // Data that barely shrinks is cheaper to send as-is.
+        let (kind, body) = if len < data.len() - data.len() / 8 {
+            (COMPRESSED, &self.scratch[..len])
+        } else {
+            (UNCOMPRESSED, data)
+        };
+
+        let len = CHECKSUM_LENGTH + body.len();
+        self.write_buf.reserve(CHUNK_HEADER_LENGTH + len);
+        self.write_buf.put_u8(kind);
+        self.write_buf.put_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8]);
+        self.write_buf.put_u32_le(checksum);
+        self.write_buf.put_slice(body);
+        Ok(())
+    }
+}
+
+impl<T: Write> SnappyStream<T> {
+    fn write_chunks(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            let n = self.inner.write(&self.write_buf)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write snappy chunk"));
+            }
+            self.write_buf.advance(n);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for SnappyStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.plain.is_empty() {
+            if self.decode_chunk()? {
+                continue;
+            }
+
+            let mut data = [0u8; 8192];
+            let n = self.inner.read(&mut data)?;
+            if n == 0 {
+                if !self.read_buf.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed within a snappy chunk"));
+                }
+                return Ok(0);
+            }
+            self.read_buf.extend_from_slice(&data[..n]);
+        }
+
+        let n = cmp::min(buf.len(), self.plain.len());
+        buf[..n].copy_from_slice(&self.plain[..n]);
+        self.plain.advance(n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for SnappyStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Hold at most about one block back, the rest waits for the connection.
+        if self.write_buf.len() >= MAX_BLOCK_SIZE {
+            self.write_chunks()?;
+        }
+
+        let n = cmp::min(buf.len(), MAX_BLOCK_SIZE);
+        self.encode_chunk(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunks()?;
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for SnappyStream<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for SnappyStream<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.write_chunks() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            res => res?,
+        }
+        self.inner.shutdown()
+    }
+}
+
+// The checksum is masked so that data containing checksums checks well.
+fn masked_crc(data: &[u8]) -> u32 {
+    let crc = crc32c(data);
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use snap::read::FrameDecoder;
+    use snap::write::FrameEncoder;
+    use compression::testing::{read_all, Trickle};
+
+    // Reads everything, the inner reader yields one byte at a time and
+    // blocks in between.
+    fn read_trickled(data: &[u8]) -> Vec<u8> {
+        read_all(SnappyStream::new(Trickle::new(data), BytesMut::new()))
+    }
+
+    fn written(data: &[&[u8]]) -> Vec<u8> {
+        let mut stream = SnappyStream::new(Cursor::new(Vec::new()), BytesMut::new());
+        for data in data {
+            stream.write_all(data).unwrap();
+        }
+        stream.flush().unwrap();
+        stream.inner.into_inner()
+    }
+
+    // Encoded by the reference implementation of the framing format.
+    fn reference_encoded(data: &[u8]) -> Vec<u8> {
+        let mut encoder = FrameEncoder::new(Vec::new());
+        encoder.write_all(data).unwrap();
+        encoder.into_inner().unwrap()
+    }
+
+    fn reference_decoded(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        FrameDecoder::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut data = vec![b'a'; 3 * MAX_BLOCK_SIZE + 17];
+        data.extend((0..5000u32).map(|i| (i * 7919 % 251) as u8));
+        data
+    }
+
+    #[test]
+    fn reads_reference_output() {
+        assert_eq!(read_trickled(&reference_encoded(b"OK")), b"OK");
+        assert_eq!(read_trickled(&reference_encoded(&sample())), sample());
+    }
+
+    #[test]
+    fn writes_what_the_reference_reads() {
+        let encoded = written(&[b"NOP\n", b"", &sample()]);
+        assert!(encoded.len() < sample().len() / 10);
+
+        let mut expected = b"NOP\n".to_vec();
+        expected.extend_from_slice(&sample());
+        assert_eq!(reference_decoded(&encoded), expected);
+        assert_eq!(read_trickled(&encoded), expected);
+    }
+
+    #[test]
+    fn buffered_data_is_read_first() {
+        let encoded = reference_encoded(b"OK");
+        let (buffered, rest) = encoded.split_at(12);
+        let mut stream = SnappyStream::new(Cursor::new(rest.to_vec()), BytesMut::from(buffered));
+
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"OK");
+    }
+
+    #[test]
+    fn corrupt_chunks_are_rejected() {
+        let mut encoded = reference_encoded(b"OK");
+        *encoded.last_mut().unwrap() = b'X';
+        let mut stream = SnappyStream::new(Cursor::new(encoded), BytesMut::new());
+        let err = stream.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut stream = SnappyStream::new(Cursor::new(b"\x02\x00\x00\x00".to_vec()), BytesMut::new());
+        assert!(stream.read(&mut [0u8; 16]).is_err());
+    }
+}
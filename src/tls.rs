@@ -0,0 +1,43 @@
+use std::io;
+
+use futures::{future, Future};
+use native_tls::{self, Certificate, Identity};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::{TlsConnector, TlsStream};
+
+use config::TlsConfig;
+use error::NsqError;
+
+/// Runs the TLS handshake on a connection nsqd agreed to upgrade.
+///
+/// `server_name` is checked against the certificate unless
+/// `TlsConfig::server_name` overrides it.
+pub fn upgrade<T>(io: T, config: &TlsConfig, server_name: &str) -> Box<dyn Future<Item = TlsStream<T>, Error = NsqError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let connector = match connector(config) {
+        Ok(connector) => connector,
+        Err(err) => return Box::new(future::err(tls_error(err))),
+    };
+    let server_name = config.server_name.as_ref().map_or(server_name, |name| name.as_str());
+
+    Box::new(connector.connect(server_name, io).map_err(tls_error))
+}
+
+fn connector(config: &TlsConfig) -> Result<TlsConnector, native_tls::Error> {
+    let mut builder = native_tls::TlsConnector::builder();
+    for pem in &config.root_certificates {
+        builder.add_root_certificate(Certificate::from_pem(pem)?);
+    }
+    if let Some((ref cert, ref key)) = config.client_certificate {
+        builder.identity(Identity::from_pkcs8(cert, key)?);
+    }
+    builder.danger_accept_invalid_certs(config.insecure_skip_verify);
+    builder.danger_accept_invalid_hostnames(config.insecure_skip_verify);
+
+    Ok(builder.build()?.into())
+}
+
+fn tls_error(err: native_tls::Error) -> NsqError {
+    io::Error::other(err).into()
+}
@@ -0,0 +1,177 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::sync::oneshot;
+use tokio_codec::Framed;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use codec::{NsqCodec, NsqFrame, HEARTBEAT};
+use event::{ConnectionEvent, EventHook};
+use commands::Command;
+use rdy::ConnectionRdy;
+
+/// Transport the client runs on once the handshake is done.
+///
+/// Heartbeats are answered with NOP here and never reach the client.
+///
+/// For consumers, messages received and finished are counted here and the
+/// RDY updates they call for are sent by the transport as well, once the
+/// first RDY after subscribing was.
+pub struct NsqTransport<T> {
+    inner: Framed<T, NsqCodec>,
+    subscribed: bool,
+    // NOPs owed to nsqd in answer to heartbeats.
+    nops: usize,
+    stall: Option<(Timeout, Duration)>,
+    events: EventHook,
+    rdy: Option<ConnectionRdy>,
+    // Dropped along with the transport, once the client is done with it.
+    closing: Option<oneshot::Sender<()>>,
+}
+
+impl<T: AsyncRead + AsyncWrite> NsqTransport<T> {
+    /// Wraps a framed connection. A positive `heartbeat_interval` (in
+    /// milliseconds) enables stalled connection detection.
+    pub fn new(inner: Framed<T, NsqCodec>, heartbeat_interval: i64, handle: &Handle, events: EventHook, rdy: Option<ConnectionRdy>)
+        -> io::Result<NsqTransport<T>>
+    {
+        let stall = if heartbeat_interval > 0 {
+            let limit = Duration::from_millis(2 * heartbeat_interval as u64);
+            Some((Timeout::new(limit, handle)?, limit))
+        } else {
+            None
+        };
+
+        Ok(NsqTransport {
+            inner,
+            subscribed: false,
+            nops: 0,
+            stall,
+            events,
+            rdy,
+            closing: None,
+        })
+    }
+
+    /// Gives back the RDY slot of a message that is neither finished nor
+    /// requeued, but left to time out.
+    pub fn release(&self) {
+        if let Some(ref rdy) = self.rdy {
+            rdy.finished();
+        }
+    }
+
+    /// Resolves, as canceled, once the connection is closed and the
+    /// transport dropped.
+    pub fn closed(&mut self) -> oneshot::Receiver<()> {
+        let (closing, closed) = oneshot::channel();
+        self.closing = Some(closing);
+        closed
+    }
+
+    // Sends the NOPs and RDY updates the transport owes nsqd.
+    fn flush_owed(&mut self) -> io::Result<()> {
+        while self.nops > 0 {
+            match self.inner.start_send(Command::Nop)? {
+                AsyncSink::Ready => self.nops -= 1,
+                AsyncSink::NotReady(_) => break,
+            }
+        }
+        if self.subscribed {
+            if let Some(count) = self.rdy.as_ref().and_then(ConnectionRdy::poll_update) {
+                if let AsyncSink::NotReady(_) = self.inner.start_send(Command::Rdy(count))? {
+                    // Tried again once the connection takes more.
+                    self.rdy.as_ref().unwrap().retry(count);
+                }
+            }
+        }
+        self.inner.poll_complete()?;
+        Ok(())
+    }
+
+    fn reset_stall(&mut self) {
+        if let Some((ref mut timeout, limit)) = self.stall {
+            timeout.reset(Instant::now() + limit);
+        }
+    }
+
+    fn poll_stall(&mut self) -> io::Result<()> {
+        if let Some((ref mut timeout, _)) = self.stall {
+            if timeout.poll()?.is_ready() {
+                self.events.emit(ConnectionEvent::Stalled);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no data received for two heartbeat intervals"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Stream for NsqTransport<T> {
+    type Item = NsqFrame;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<NsqFrame>, io::Error> {
+        loop {
+            let frame = match self.inner.poll()? {
+                Async::Ready(Some(frame)) => frame,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => {
+                    self.poll_stall()?;
+                    return Ok(Async::NotReady);
+                }
+            };
+            self.reset_stall();
+
+            match frame {
+                NsqFrame::Response(ref response) if response == HEARTBEAT => {
+                    self.nops += 1;
+                    self.flush_owed()?;
+                    self.events.emit(ConnectionEvent::Heartbeat);
+                }
+                NsqFrame::Message(message) => {
+                    if let Some(ref rdy) = self.rdy {
+                        rdy.received();
+                    }
+                    return Ok(Async::Ready(Some(NsqFrame::Message(message))));
+                }
+                frame => return Ok(Async::Ready(Some(frame))),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Sink for NsqTransport<T> {
+    type SinkItem = Command;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, command: Command) -> StartSend<Command, io::Error> {
+        let (command, subscribes, finishes) = match command {
+            Command::Rdy(count) if !self.subscribed => {
+                // Consumers start with their share of max_in_flight.
+                let count = self.rdy.as_ref().map_or(count, ConnectionRdy::subscribed);
+                (Command::Rdy(count), true, false)
+            }
+            command @ Command::Fin(_) |
+            command @ Command::Req { .. } => (command, false, true),
+            command => (command, false, false),
+        };
+
+        let res = self.inner.start_send(command)?;
+        if finishes && res.is_ready() {
+            if let Some(ref rdy) = self.rdy {
+                rdy.finished();
+            }
+        }
+        if subscribes && res.is_ready() {
+            self.subscribed = true;
+        }
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.flush_owed()?;
+        self.inner.poll_complete()
+    }
+}
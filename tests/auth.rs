@@ -0,0 +1,90 @@
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::error::NsqError;
+use nsqueue::producer::Producer;
+use nsqueue::response::AuthIdentity;
+
+use common::{accept_identify, frame, read_body, read_command};
+
+const AUTH_REQUIRED: &[u8] = br#"{"max_rdy_count":2500,"auth_required":true}"#;
+
+/// Connects with `s3cr3t` to a stand-in answering AUTH with `reply`.
+fn connect(reply: Vec<u8>) -> Result<Producer, NsqError> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, mut writer) = accept_identify(listener, AUTH_REQUIRED);
+        assert_eq!(read_command(&mut reader), "AUTH\n");
+        assert_eq!(read_body(&mut reader), b"s3cr3t");
+        writer.write_all(&reply).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config::default().auth_secret("s3cr3t".into());
+    let conn = core.run(Producer::connect(&addr, &handle, config));
+    server.join().unwrap();
+    conn
+}
+
+#[test]
+fn identity_is_reported() {
+    let reply = frame(0, br#"{"identity":"worker@example.com","identity_url":"https://auth.example.com/worker","permission_count":3}"#);
+    let conn = connect(reply).unwrap();
+
+    assert!(conn.features().unwrap().auth_required);
+    assert_eq!(conn.identity(), Some(&AuthIdentity {
+        identity: "worker@example.com".into(),
+        identity_url: "https://auth.example.com/worker".into(),
+        permission_count: 3,
+    }));
+}
+
+#[test]
+fn rejected_secrets_are_typed_errors() {
+    match connect(frame(1, b"E_AUTH_FAILED AUTH failed")) {
+        Err(NsqError::AuthFailed(ref text)) => assert_eq!(text, "AUTH failed"),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("authenticated"),
+    }
+
+    match connect(frame(1, b"E_UNAUTHORIZED AUTH no authorizations found")) {
+        Err(NsqError::Unauthorized(ref text)) => assert_eq!(text, "AUTH no authorizations found"),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("authenticated"),
+    }
+}
+
+#[test]
+fn no_auth_is_sent_unless_required() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, mut writer) = accept_identify(listener, br#"{"max_rdy_count":2500}"#);
+        let command = read_command(&mut reader);
+        read_body(&mut reader);
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        command
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config::default().auth_secret("s3cr3t".into());
+    let conn = core.run(Producer::connect(&addr, &handle, config)).unwrap();
+
+    assert!(conn.identity().is_none());
+    assert_eq!(core.run(conn.publish("topic".into(), "hello")).unwrap(), "OK");
+    assert_eq!(server.join().unwrap(), "PUB topic\n");
+}
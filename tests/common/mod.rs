@@ -0,0 +1,93 @@
+//! A scripted nsqd, driven from a thread with blocking std sockets.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+pub fn frame(frame_type: u32, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&((4 + data.len()) as u32).to_be_bytes());
+    frame.extend_from_slice(&frame_type.to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+pub fn message(id: &[u8; 16], body: &[u8]) -> Vec<u8> {
+    attempted_message(id, 1, body)
+}
+
+pub fn attempted_message(id: &[u8; 16], attempts: u16, body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&0i64.to_be_bytes());
+    data.extend_from_slice(&attempts.to_be_bytes());
+    data.extend_from_slice(id);
+    data.extend_from_slice(body);
+    frame(2, &data)
+}
+
+pub fn read_command<R: BufRead>(reader: &mut R) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line
+}
+
+/// Reads the next command that is not a RDY update, as sent when the
+/// connections of a consumer come and go.
+pub fn read_ack<R: BufRead>(reader: &mut R) -> String {
+    loop {
+        let command = read_command(reader);
+        if !command.starts_with("RDY ") {
+            return command;
+        }
+    }
+}
+
+/// Reads the size-prefixed body following a command.
+pub fn read_body<R: BufRead>(reader: &mut R) -> Vec<u8> {
+    let mut size = [0u8; 4];
+    reader.read_exact(&mut size).unwrap();
+    let mut body = vec![0u8; u32::from_be_bytes(size) as usize];
+    reader.read_exact(&mut body).unwrap();
+    body
+}
+
+/// Accepts one client and reads the magic and IDENTIFY, leaving the
+/// answer to the caller.
+pub fn read_identify(listener: TcpListener) -> (BufReader<TcpStream>, TcpStream) {
+    let (stream, _) = listener.accept().unwrap();
+    let writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).unwrap();
+    assert_eq!(&magic, b"  V2");
+
+    assert_eq!(read_command(&mut reader), "IDENTIFY\n");
+    read_body(&mut reader);
+
+    (reader, writer)
+}
+
+/// Accepts one client, reads the magic and IDENTIFY, and answers it with
+/// `reply`.
+pub fn accept_identify(listener: TcpListener, reply: &[u8]) -> (BufReader<TcpStream>, TcpStream) {
+    let (reader, mut writer) = read_identify(listener);
+    writer.write_all(&frame(0, reply)).unwrap();
+    (reader, writer)
+}
+
+/// Accepts one client and runs the handshake up to the subscription.
+pub fn accept_subscriber(listener: TcpListener) -> (BufReader<TcpStream>, TcpStream) {
+    accept_subscriber_with(listener, b"OK")
+}
+
+/// Like `accept_subscriber`, answering IDENTIFY with `reply`.
+pub fn accept_subscriber_with(listener: TcpListener, reply: &[u8]) -> (BufReader<TcpStream>, TcpStream) {
+    let (mut reader, mut writer) = accept_identify(listener, reply);
+
+    assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+    writer.write_all(&frame(0, b"OK")).unwrap();
+    assert!(read_command(&mut reader).starts_with("RDY "));
+
+    (reader, writer)
+}
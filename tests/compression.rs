@@ -0,0 +1,138 @@
+extern crate flate2;
+extern crate futures;
+extern crate snap;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::{BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use futures::{Future, Stream};
+use snap::read::FrameDecoder;
+use snap::write::FrameEncoder;
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::error::NsqError;
+use nsqueue::producer::Producer;
+
+use common::{frame, message, read_body, read_command, read_identify};
+
+#[test]
+fn snappy_carries_everything_after_identify() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (reader, writer) = read_identify(listener);
+
+        // The compressed OK goes out in the same write as the IDENTIFY
+        // response, the client has to pick it up from what it buffered.
+        let mut encoder = FrameEncoder::new(Vec::new());
+        encoder.write_all(&frame(0, b"OK")).unwrap();
+        let mut reply = frame(0, br#"{"max_rdy_count":2500,"snappy":true}"#);
+        reply.extend_from_slice(&encoder.into_inner().unwrap());
+        (&writer).write_all(&reply).unwrap();
+
+        let mut reader = BufReader::new(FrameDecoder::new(reader.into_inner()));
+        let mut writer = FrameEncoder::new(writer);
+        let mut send = |data: &[u8]| {
+            writer.write_all(data).unwrap();
+            writer.flush().unwrap();
+        };
+
+        assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+        send(&frame(0, b"OK"));
+        assert_eq!(read_command(&mut reader), "RDY 1\n");
+        send(&frame(0, b"_heartbeat_"));
+        assert_eq!(read_command(&mut reader), "NOP\n");
+        send(&message(b"0123456789abcdef", &[b'x'; 10000]));
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config::default().snappy(true);
+
+    let received = core.run(
+        Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| {
+            assert!(conn.features().unwrap().snappy);
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(|stream| stream.into_future().map_err(|(e, _)| e))
+                .map(move |(message, _)| {
+                    let message = message.unwrap();
+                    let _ = conn.fin(message.message_id);
+                    message
+                })
+        })
+    ).unwrap();
+
+    assert_eq!(&received.message_body[..], &[b'x'; 10000][..]);
+    core.turn(Some(std::time::Duration::from_millis(100)));
+    server.join().unwrap();
+}
+
+#[test]
+fn deflate_carries_everything_after_identify() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (reader, writer) = read_identify(listener);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(3));
+        encoder.write_all(&frame(0, b"OK")).unwrap();
+        encoder.flush().unwrap();
+        let mut reply = frame(0, br#"{"max_rdy_count":2500,"deflate":true,"deflate_level":3,"max_deflate_level":3}"#);
+        reply.extend_from_slice(encoder.get_ref());
+        (&writer).write_all(&reply).unwrap();
+
+        // The decoder waits for more input before handing over output it
+        // holds back, reads have to take whole commands.
+        let mut reader = BufReader::with_capacity(65536, DeflateDecoder::new(reader.into_inner()));
+        let mut writer = DeflateEncoder::new(writer, Compression::new(3));
+        let mut send = |data: &[u8]| {
+            writer.write_all(data).unwrap();
+            writer.flush().unwrap();
+        };
+
+        assert_eq!(read_command(&mut reader), "PUB topic\n");
+        read_body(&mut reader);
+        send(&frame(0, b"OK"));
+        assert_eq!(read_command(&mut reader), "PUB topic\n");
+        assert_eq!(read_body(&mut reader), vec![b'y'; 10000]);
+        send(&frame(0, b"OK"));
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let conn = core.run(Producer::connect(&addr, &handle, Config::default().deflate(9))).unwrap();
+    assert_eq!(conn.features().unwrap().max_deflate_level, 3);
+    assert_eq!(core.run(conn.publish("topic".into(), "first")).unwrap(), "OK");
+    assert_eq!(core.run(conn.publish("topic".into(), vec![b'y'; 10000])).unwrap(), "OK");
+    server.join().unwrap();
+}
+
+#[test]
+fn snappy_and_deflate_are_rejected_before_connecting() {
+    // Nothing listens there, the config has to be refused first.
+    let addr = "127.0.0.1:1".parse().unwrap();
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config::default().snappy(true).deflate(6);
+
+    match core.run(Producer::connect(&addr, &handle, config)) {
+        Err(NsqError::IOError(ref err)) if err.kind() == std::io::ErrorKind::InvalidInput => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("connected with both snappy and deflate"),
+    }
+}
@@ -0,0 +1,85 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::dead_letter::DeadLetter;
+
+use common::{accept_identify, attempted_message, frame, read_body, read_command};
+
+#[test]
+fn failed_messages_are_published_to_the_dead_letter_topic() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        // The consumer connection, then the one dead letters go through.
+        let (mut reader, mut writer) = accept_identify(listener.try_clone().unwrap(), b"OK");
+        let (mut dlq_reader, mut dlq_writer) = accept_identify(listener, b"OK");
+        assert_eq!(read_command(&mut reader), "SUB orders billing\n");
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        assert_eq!(read_command(&mut reader), "RDY 1\n");
+
+        // Finished once published.
+        writer.write_all(&attempted_message(b"0000000000000001", 2, b"{\"id\":1}")).unwrap();
+        assert_eq!(read_command(&mut dlq_reader), "PUB orders.dlq\n");
+        let letter = DeadLetter::decode(&read_body(&mut dlq_reader)).unwrap();
+        assert_eq!(letter, DeadLetter {
+            topic: "orders".into(),
+            channel: "billing".into(),
+            attempts: 2,
+            error: Some("card declined".into()),
+            first_seen: UNIX_EPOCH,
+            body: "{\"id\":1}".into(),
+        });
+        dlq_writer.write_all(&frame(0, b"OK")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0000000000000001\n");
+
+        // Requeued when publishing failed.
+        writer.write_all(&attempted_message(b"0000000000000002", 2, b"{\"id\":2}")).unwrap();
+        assert_eq!(read_command(&mut dlq_reader), "PUB orders.dlq\n");
+        read_body(&mut dlq_reader);
+        dlq_writer.write_all(&frame(1, b"E_PUB_FAILED PUB failed exiting")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0000000000000002 180000\n");
+
+        // Delivered past max_attempts, never handled.
+        writer.write_all(&attempted_message(b"0000000000000002", 3, b"{\"id\":2}")).unwrap();
+        assert_eq!(read_command(&mut dlq_reader), "PUB orders.dlq\n");
+        let letter = DeadLetter::decode(&read_body(&mut dlq_reader)).unwrap();
+        assert_eq!((letter.attempts, letter.error), (3, None));
+        dlq_writer.write_all(&frame(0, b"OK")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0000000000000002\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { max_backoff_duration: 0, ..Config::default() }
+        .dead_letter_topic(2, "{topic}.dlq".into());
+
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| {
+            conn.subscribe("orders".into(), "billing".into())
+                .and_then(move |stream| conn.handle(stream, |message| {
+                    assert!(message.attempts < 3, "handled past max_attempts");
+                    Err("card declined")
+                }))
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+}
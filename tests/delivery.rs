@@ -0,0 +1,88 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+
+use nsqueue::config::{Config, DropAction};
+use nsqueue::consumer::Consumer;
+use nsqueue::response::Delivery;
+
+use common::{accept_subscriber, attempted_message, message, read_command};
+
+fn run<F, S>(config: Config, server: S, ack: F)
+    where F: Fn(Delivery) + 'static,
+          S: FnOnce(TcpListener) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        server(listener);
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| conn.subscribe("topic".into(), "channel".into()))
+        .and_then(move |stream| stream.for_each(move |delivery| {
+            ack(delivery);
+            Ok(())
+        }))
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn deliveries_acknowledge_themselves() {
+    let config = Config { max_backoff_duration: 0, ..Config::default() };
+    run(config, |listener| {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"finish")).unwrap();
+        assert_eq!(read_command(&mut reader), "TOUCH 0123456789abcdef\n");
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+        writer.write_all(&message(b"0123456789abcdeg", b"requeue")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0123456789abcdeg 500\n");
+    }, |delivery| {
+        if &delivery.message_body[..] == b"finish" {
+            drop(delivery.touch());
+            drop(delivery.finish());
+        } else {
+            drop(delivery.requeue(Duration::from_millis(500)));
+        }
+    });
+}
+
+#[test]
+fn dropped_deliveries_are_requeued() {
+    let config = Config { max_backoff_duration: 0, ..Config::default() };
+    run(config, |listener| {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&attempted_message(b"0123456789abcdef", 2, b"lost")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0123456789abcdef 180000\n");
+    }, drop);
+}
+
+#[test]
+fn the_drop_action_is_configurable() {
+    let config = Config { on_drop: DropAction::Finish, ..Config::default() };
+    run(config, |listener| {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"lost")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+    }, drop);
+}
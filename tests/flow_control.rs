@@ -0,0 +1,126 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+use nsqueue::backoff::ConstantBackoff;
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+
+use common::{accept_identify, frame, message, read_command};
+
+// Runs a consumer subscribed to "topic" against a scripted nsqd, which
+// answers IDENTIFY with `reply` and gets the connection once subscribed.
+// `subscribed` runs before the initial RDY is written. Messages are
+// handled successfully unless their body is "fail".
+fn run<F, S>(config: Config, reply: &'static [u8], subscribed: F, server: S)
+    where F: FnOnce(&Consumer, &Handle) + 'static,
+          S: FnOnce(&mut BufReader<TcpStream>, &mut TcpStream) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_identify(listener, reply);
+        assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        server(&mut reader, &mut writer);
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let hooked = handle.clone();
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| {
+            conn.subscribe("topic".into(), "channel".into())
+                .map(move |stream| {
+                    subscribed(&conn, &hooked);
+                    conn.handle(stream, |message| {
+                        if &message.message_body[..] == b"fail" { Err("failed") } else { Ok(()) }
+                    })
+                })
+        })
+        .map_err(|err| panic!("{}", err))
+        .and_then(|handled| handled.map_err(|err| panic!("{}", err)));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn rdy_is_max_in_flight() {
+    let config = Config { max_in_flight: 50, ..Config::default() };
+    run(config, b"OK", |_, _| {}, |reader, _| {
+        assert_eq!(read_command(reader), "RDY 50\n");
+    });
+}
+
+#[test]
+fn rdy_is_capped_at_max_rdy_count() {
+    let config = Config { max_in_flight: 50, ..Config::default() };
+    run(config, br#"{"max_rdy_count":20}"#, |_, _| {}, |reader, _| {
+        assert_eq!(read_command(reader), "RDY 20\n");
+    });
+}
+
+#[test]
+fn rdy_starts_at_max_in_flight_raised_before() {
+    run(Config::default(), b"OK", |conn, _| conn.set_max_in_flight(5), |reader, _| {
+        assert_eq!(read_command(reader), "RDY 5\n");
+    });
+}
+
+#[test]
+fn rdy_follows_max_in_flight() {
+    let raise = |conn: &Consumer, handle: &Handle| {
+        let raised = conn.clone();
+        let timeout = Timeout::new(Duration::from_millis(50), handle).unwrap();
+        handle.spawn(timeout.then(move |_| {
+            raised.set_max_in_flight(5);
+            Ok(())
+        }));
+    };
+    run(Config::default(), b"OK", raise, |reader, writer| {
+        assert_eq!(read_command(reader), "RDY 1\n");
+        assert_eq!(read_command(reader), "RDY 5\n");
+
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_command(reader), "FIN 0123456789abcdef\n");
+    });
+}
+
+// RDY and the REQ or FIN that caused it are sent independently.
+fn read_commands(reader: &mut BufReader<TcpStream>, count: usize) -> Vec<String> {
+    let mut commands: Vec<_> = (0..count).map(|_| read_command(reader)).collect();
+    commands.sort();
+    commands
+}
+
+#[test]
+fn failures_back_off() {
+    let config = Config { max_in_flight: 5, backoff_multiplier: 50, ..Config::default() };
+    run(config, b"OK", |conn, _| conn.set_backoff_strategy(ConstantBackoff), |reader, writer| {
+        assert_eq!(read_command(reader), "RDY 5\n");
+
+        writer.write_all(&message(b"0123456789abcdef", b"fail")).unwrap();
+        assert_eq!(read_commands(reader, 2), vec!["RDY 0\n", "REQ 0123456789abcdef 90000\n"]);
+        // Tests with a single message once the interval passed.
+        assert_eq!(read_command(reader), "RDY 1\n");
+
+        writer.write_all(&message(b"0123456789abcdeg", b"payload")).unwrap();
+        assert_eq!(read_commands(reader, 2), vec!["FIN 0123456789abcdeg\n", "RDY 5\n"]);
+    });
+}
@@ -0,0 +1,94 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, IntoFuture};
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::handler::Handler;
+use nsqueue::response::Message;
+
+use common::{accept_subscriber, message, read_command};
+
+// Takes as many milliseconds as the body says, fails on "fail".
+struct Sleeper {
+    handle: Handle,
+}
+
+impl Handler for Sleeper {
+    type Error = String;
+    type Future = Box<dyn Future<Item = (), Error = String>>;
+
+    fn handle(&self, message: &Message) -> Self::Future {
+        if &message.message_body[..] == b"fail" {
+            return Box::new(Err("failed".to_string()).into_future());
+        }
+        let took = String::from_utf8_lossy(&message.message_body).parse().unwrap();
+        Box::new(Timeout::new(Duration::from_millis(took), &self.handle).unwrap()
+            .map_err(|err| err.to_string()))
+    }
+}
+
+fn run<F>(config: Config, concurrency: usize, server: F)
+    where F: FnOnce(TcpListener) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        server(listener);
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let handler = Sleeper { handle: handle.clone() };
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(move |conn| {
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| conn.add_handler(stream, handler, concurrency))
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn messages_are_handled_concurrently() {
+    let config = Config { max_in_flight: 3, ..Config::default() };
+    run(config, 3, |listener| {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"400")).unwrap();
+        writer.write_all(&message(b"0123456789abcdeg", b"200")).unwrap();
+        writer.write_all(&message(b"0123456789abcdeh", b"0")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdeh\n");
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdeg\n");
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+    });
+}
+
+#[test]
+fn concurrency_bounds_the_handlers_running() {
+    let config = Config { max_in_flight: 2, max_backoff_duration: 0, ..Config::default() };
+    run(config, 1, |listener| {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"200")).unwrap();
+        writer.write_all(&message(b"0123456789abcdeg", b"fail")).unwrap();
+        // The failing one waits for the slow one.
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+        assert!(read_command(&mut reader).starts_with("REQ 0123456789abcdeg "));
+    });
+}
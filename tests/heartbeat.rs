@@ -0,0 +1,101 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::rc::Rc;
+use std::thread;
+
+use futures::{Future, Stream};
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::event::ConnectionEvent;
+
+use common::{accept_subscriber, frame, message, read_command};
+
+#[test]
+fn heartbeats_are_answered_and_not_streamed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+
+        writer.write_all(&frame(0, b"_heartbeat_")).unwrap();
+        assert_eq!(read_command(&mut reader), "NOP\n");
+
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let heartbeats = Rc::new(Cell::new(0));
+    let seen = heartbeats.clone();
+
+    let received = core.run(
+        Consumer::connect(&addr, &handle, Config::default())
+        .and_then(move |conn| {
+            conn.on_event(move |event| {
+                if event == ConnectionEvent::Heartbeat {
+                    seen.set(seen.get() + 1);
+                }
+            });
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(|stream| stream.into_future().map_err(|(e, _)| e))
+                .map(move |(message, _)| {
+                    let message = message.unwrap();
+                    let _ = conn.fin(message.message_id);
+                    message
+                })
+        })
+    ).unwrap();
+
+    assert_eq!(&received.message_body[..], b"payload");
+    assert_eq!(heartbeats.get(), 1);
+    core.turn(Some(std::time::Duration::from_millis(100)));
+    server.join().unwrap();
+}
+
+#[test]
+fn silent_connections_are_detected_as_stalled() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, _writer) = accept_subscriber(listener);
+        // Wait for the client to give up on us.
+        let mut rest = Vec::new();
+        let _ = reader.read_to_end(&mut rest);
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let stalled = Rc::new(Cell::new(false));
+    let seen = stalled.clone();
+    // Not reconnecting, the stream ends along with the connection.
+    let config = Config { heartbeat_interval: 50, reconnect_delay: 0, ..Config::default() };
+
+    let _ = core.run(
+        Consumer::connect(&addr, &handle, config)
+        .and_then(move |conn| {
+            conn.on_event(move |event| {
+                if event == ConnectionEvent::Stalled {
+                    seen.set(true);
+                }
+            });
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(|stream| stream.for_each(|_| Ok(())))
+        })
+    );
+
+    assert!(stalled.get());
+    drop(core);
+    server.join().unwrap();
+}
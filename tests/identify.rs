@@ -0,0 +1,96 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use futures::{Future, Stream};
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::error::NsqError;
+use nsqueue::producer::Producer;
+
+use common::{accept_identify, frame, message, read_command};
+
+const FEATURES: &[u8] = br#"{"max_rdy_count":2,"version":"1.2.1","max_msg_timeout":900000,"msg_timeout":60000,"tls_v1":false,"deflate":false,"deflate_level":6,"max_deflate_level":6,"snappy":false,"sample_rate":0,"auth_required":false,"output_buffer_size":16384,"output_buffer_timeout":250}"#;
+
+#[test]
+fn negotiated_features_are_exposed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, mut writer) = accept_identify(listener, FEATURES);
+        assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        let rdy = read_command(&mut reader);
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        rdy
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let conn = core.run(Consumer::connect(&addr, &handle, Config::default())).unwrap();
+    {
+        let features = conn.features().unwrap();
+        assert_eq!(features.version, "1.2.1");
+        assert_eq!(features.max_rdy_count, 2);
+        assert_eq!(features.msg_timeout, 60000);
+        assert_eq!(features.max_msg_timeout, 900000);
+        assert_eq!(features.max_deflate_level, 6);
+        assert!(!features.auth_required);
+    }
+
+    let stream = core.run(conn.subscribe("topic".into(), "channel".into())).unwrap();
+    let _ = core.run(stream.into_future().map_err(|(e, _)| e)).unwrap();
+    assert_eq!(server.join().unwrap(), "RDY 1\n");
+}
+
+#[test]
+fn plain_ok_means_nothing_was_negotiated() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        accept_identify(listener, b"OK");
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { feature_negotiation: false, ..Config::default() };
+
+    let conn = core.run(Producer::connect(&addr, &handle, config)).unwrap();
+    assert!(conn.features().is_none());
+    server.join().unwrap();
+}
+
+#[test]
+fn auth_required_without_secret_is_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, _writer) = accept_identify(listener, br#"{"max_rdy_count":2500,"auth_required":true}"#);
+        // Nothing else is sent, the client hangs up.
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        rest
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    match core.run(Producer::connect(&addr, &handle, Config::default())) {
+        Err(NsqError::AuthRequired) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("connected without a secret"),
+    }
+    assert!(server.join().unwrap().is_empty());
+}
@@ -0,0 +1,285 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::future::Either;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Timeout};
+
+use nsqueue::config::{Config, DropAction};
+use nsqueue::consumer::Consumer;
+use nsqueue::response::Message;
+
+use common::{accept_identify, accept_subscriber, frame, message, read_ack, read_command};
+
+// Stands in for nsqlookupd, answering each lookup with the nsqd instances
+// `producers` lists at that moment.
+fn lookupd(producers: Arc<Mutex<Vec<SocketAddr>>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            assert_eq!(request, "GET /lookup?topic=topic HTTP/1.0\r\n");
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+            }
+
+            let listed: Vec<String> = producers.lock().unwrap().iter()
+                .map(|addr| format!(
+                    r#"{{"hostname":"nowhere.invalid","broadcast_address":"{}","tcp_port":{},"http_port":4151}}"#,
+                    addr.ip(), addr.port()
+                ))
+                .collect();
+            let body = format!(r#"{{"channels":["channel"],"producers":[{}]}}"#, listed.join(","));
+            write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}", body).unwrap();
+        }
+    });
+
+    url
+}
+
+fn consume(url: String, config: Config, core: &mut Core) {
+    let handle = core.handle();
+    let consumer = Consumer::connect_to_lookupds(&[url], "topic".into(), "channel".into(), &handle, config)
+        .and_then(|(consumer, stream)| {
+            consumer.add_handler(stream, |_: &Message| Ok::<_, String>(()), 2)
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+}
+
+#[test]
+fn deliveries_of_every_nsqd_listed_are_merged() {
+    let mut finished = Vec::new();
+    let mut producers = Vec::new();
+    for id in [b"000000000000000a", b"000000000000000b"] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        producers.push(listener.local_addr().unwrap());
+        let (done, ended) = oneshot::channel();
+        finished.push(ended);
+
+        thread::spawn(move || {
+            let (mut reader, mut writer) = accept_subscriber(listener);
+            writer.write_all(&message(id, b"payload")).unwrap();
+            // Acknowledged on the connection the message came from.
+            assert_eq!(read_ack(&mut reader), format!("FIN {}\n", String::from_utf8_lossy(id)));
+            done.send(()).unwrap();
+        });
+    }
+    let url = lookupd(Arc::new(Mutex::new(producers)));
+
+    let mut core = Core::new().unwrap();
+    let config = Config { max_in_flight: 2, ..Config::default() };
+    consume(url, config, &mut core);
+
+    core.run(futures::future::join_all(finished)).expect("server failed");
+}
+
+#[test]
+fn nsqds_no_longer_listed_are_dropped() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let producers = Arc::new(Mutex::new(vec![listener.local_addr().unwrap()]));
+    let url = lookupd(producers.clone());
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_ack(&mut reader), "FIN 0123456789abcdef\n");
+
+        producers.lock().unwrap().clear();
+        assert_eq!(read_ack(&mut reader), "CLS\n");
+        writer.write_all(&frame(0, b"CLOSE_WAIT")).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let config = Config { lookupd_poll_interval: 50, lookupd_poll_jitter: 0.5, ..Config::default() };
+    consume(url, config, &mut core);
+
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn silent_nsqlookupd_do_not_hold_up_the_others() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = lookupd(Arc::new(Mutex::new(vec![listener.local_addr().unwrap()])));
+    // Connections are accepted by the kernel, nothing is ever answered.
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_ack(&mut reader), "FIN 0123456789abcdef\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let urls = [format!("http://{}", silent.local_addr().unwrap()), url];
+    let config = Config { lookupd_timeout: 100, ..Config::default() };
+    let consumer = Consumer::connect_to_lookupds(&urls, "topic".into(), "channel".into(), &handle, config)
+        .and_then(|(consumer, stream)| {
+            consumer.add_handler(stream, |_: &Message| Ok::<_, String>(()), 2)
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn silent_nsqd_are_given_up_on() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // Connections are accepted by the kernel, IDENTIFY is never answered.
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let producers = vec![silent.local_addr().unwrap(), listener.local_addr().unwrap()];
+    let url = lookupd(Arc::new(Mutex::new(producers)));
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_ack(&mut reader), "FIN 0123456789abcdef\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let config = Config { dial_timeout: 100, ..Config::default() };
+    consume(url, config, &mut core);
+
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn queries_do_not_wait_for_nsqd_being_connected_to() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // Connections are accepted by the kernel, IDENTIFY is never answered.
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (silent_addr, addr) = (silent.local_addr().unwrap(), listener.local_addr().unwrap());
+    let producers = Arc::new(Mutex::new(Vec::new()));
+    let url = lookupd(producers.clone());
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_ack(&mut reader), "FIN 0123456789abcdef\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let timers = handle.clone();
+    let config = Config { lookupd_poll_interval: 50, dial_timeout: 10_000, ..Config::default() };
+    let consumer = Consumer::connect_to_lookupds(&[url], "topic".into(), "channel".into(), &handle, config)
+        .and_then(move |(consumer, stream)| {
+            // Listed one after the other, the silent one is still being
+            // connected to when the other appears.
+            producers.lock().unwrap().push(silent_addr);
+            let listed = Timeout::new(Duration::from_millis(200), &timers).unwrap();
+            timers.spawn(listed.then(move |_| {
+                producers.lock().unwrap().push(addr);
+                Ok(())
+            }));
+            consumer.add_handler(stream, |_: &Message| Ok::<_, String>(()), 2)
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    let expired = Timeout::new(Duration::from_secs(2), &handle).unwrap();
+    match core.run(finished.select2(expired)) {
+        Ok(Either::A(_)) => {}
+        _ => panic!("not connected to while the silent nsqd was"),
+    }
+    drop(silent);
+}
+
+#[test]
+fn querying_ends_with_the_consumer() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = lookupd(Arc::new(Mutex::new(vec![listener.local_addr().unwrap()])));
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, _writer) = accept_subscriber(listener);
+        reader.get_ref().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut rest = Vec::new();
+        done.send(reader.read_to_end(&mut rest).is_ok()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { lookupd_poll_interval: 20, ..Config::default() };
+    let consumer = Consumer::connect_to_lookupds(&[url], "topic".into(), "channel".into(), &handle, config);
+    drop(core.run(consumer).unwrap());
+
+    assert!(core.run(finished).unwrap(), "connection not closed");
+}
+
+#[test]
+fn ignored_messages_leave_room_for_other_connections() {
+    let first = TcpListener::bind("127.0.0.1:0").unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").unwrap();
+    let joining = second.local_addr().unwrap();
+    let producers = Arc::new(Mutex::new(vec![first.local_addr().unwrap()]));
+    let url = lookupd(producers.clone());
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_identify(first, b"OK");
+        assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        assert_eq!(read_command(&mut reader), "RDY 2\n");
+        writer.write_all(&message(b"000000000000000a", b"payload")).unwrap();
+        writer.write_all(&message(b"000000000000000b", b"payload")).unwrap();
+        // Halved once the second nsqd joins.
+        assert_eq!(read_command(&mut reader), "RDY 1\n");
+        // Closing would hand its share to the second.
+        thread::sleep(Duration::from_millis(200));
+    });
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_identify(second, b"OK");
+        assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        done.send(read_command(&mut reader)).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { max_in_flight: 2, on_drop: DropAction::Ignore, lookupd_poll_interval: 50, ..Config::default() };
+    let mut received = 0;
+    let consumer = Consumer::connect_to_lookupds(&[url], "topic".into(), "channel".into(), &handle, config)
+        .and_then(move |(_, stream)| stream.for_each(move |_| {
+            received += 1;
+            if received == 2 {
+                producers.lock().unwrap().push(joining);
+            }
+            Ok(())
+        }))
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    assert_eq!(core.run(finished).unwrap(), "RDY 1\n");
+}
@@ -0,0 +1,128 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::{BufRead, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+
+use common::{accept_identify, accept_subscriber, frame, message, read_ack, read_command};
+
+#[test]
+fn acks_go_back_to_the_nsqd_of_the_message() {
+    let mut addrs = Vec::new();
+    let mut finished = Vec::new();
+    // Both send a message with the same id, nsqd only keeps them unique
+    // per instance.
+    for body in [&b"first"[..], &b"second"[..]] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        addrs.push(listener.local_addr().unwrap());
+        let (done, ended) = oneshot::channel();
+        finished.push(ended);
+
+        thread::spawn(move || {
+            let (mut reader, mut writer) = accept_subscriber(listener);
+            writer.write_all(&message(b"0123456789abcdef", body)).unwrap();
+            assert_eq!(read_ack(&mut reader), "TOUCH 0123456789abcdef\n");
+            assert_eq!(read_ack(&mut reader), "FIN 0123456789abcdef\n");
+            done.send(()).unwrap();
+        });
+    }
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { max_in_flight: 2, ..Config::default() };
+
+    let consumer = Consumer::connect_to_nsqds(&addrs, "topic".into(), "channel".into(), &handle, config)
+        .and_then(|(_, stream)| stream.for_each(|delivery| {
+            drop(delivery.touch());
+            drop(delivery.finish());
+            Ok(())
+        }))
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(future::join_all(finished)).expect("server failed");
+}
+
+#[test]
+fn max_in_flight_is_shared() {
+    let mut addrs = Vec::new();
+    let mut finished = Vec::new();
+    let settled = Arc::new(Barrier::new(2));
+    for _ in 0..2 {
+        let settled = settled.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        addrs.push(listener.local_addr().unwrap());
+        let (done, ended) = oneshot::channel();
+        finished.push(ended);
+
+        thread::spawn(move || {
+            let (mut reader, mut writer) = accept_identify(listener, b"OK");
+            assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+            writer.write_all(&frame(0, b"OK")).unwrap();
+
+            // Whatever RDY subscribing started with, the last one sent
+            // before things quiet down is the connection's share.
+            reader.get_ref().set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            let mut last = String::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok() {
+                last = line.clone();
+                line.clear();
+            }
+            // Closing would hand this connection's share to the other.
+            settled.wait();
+            done.send(last).unwrap();
+        });
+    }
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { max_in_flight: 6, ..Config::default() };
+
+    let consumer = Consumer::connect_to_nsqds(&addrs, "topic".into(), "channel".into(), &handle, config)
+        .and_then(|(_, stream)| stream.for_each(|_| Ok(())))
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    let settled = core.run(future::join_all(finished)).expect("server failed");
+    assert_eq!(settled, vec!["RDY 3\n", "RDY 3\n"]);
+}
+
+#[test]
+fn connections_are_closed_when_another_fails() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let addrs = [listener.local_addr().unwrap(), refused];
+
+    let server = thread::spawn(move || {
+        let (mut reader, _writer) = accept_identify(listener, b"OK");
+        reader.get_ref().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let consumer = Consumer::connect_to_nsqds(&addrs, "topic".into(), "channel".into(), &handle, Config::default());
+    assert!(core.run(consumer).is_err());
+
+    // The good connection is closed while the reactor runs.
+    let (done, ended) = oneshot::channel();
+    thread::spawn(move || done.send(server.join().is_ok()).unwrap());
+    assert!(core.run(ended).unwrap(), "server failed");
+}
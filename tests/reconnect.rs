@@ -0,0 +1,211 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::cell::RefCell;
+use std::io::{ErrorKind, Write};
+use std::net::TcpListener;
+use std::rc::Rc;
+use std::thread;
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::error::NsqError;
+use nsqueue::event::ConnectionEvent;
+use nsqueue::producer::Producer;
+use nsqueue::response::Message;
+
+use common::{accept_identify, accept_subscriber, frame, message, read_ack, read_body, read_command, read_identify};
+
+const AUTH_REQUIRED: &[u8] = br#"{"max_rdy_count":2500,"auth_required":true}"#;
+
+#[test]
+fn consumers_subscribe_again_after_reconnecting() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        for id in [b"000000000000000a", b"000000000000000b"] {
+            let (mut reader, mut writer) = accept_identify(listener.try_clone().unwrap(), AUTH_REQUIRED);
+            assert_eq!(read_command(&mut reader), "AUTH\n");
+            assert_eq!(read_body(&mut reader), b"s3cr3t");
+            writer.write_all(&frame(0, br#"{"identity":"worker","identity_url":"","permission_count":1}"#)).unwrap();
+            assert_eq!(read_command(&mut reader), "SUB topic channel\n");
+            writer.write_all(&frame(0, b"OK")).unwrap();
+            assert_eq!(read_command(&mut reader), "RDY 1\n");
+
+            writer.write_all(&message(id, b"payload")).unwrap();
+            assert_eq!(read_ack(&mut reader), format!("FIN {}\n", String::from_utf8_lossy(id)));
+            // Dropping both halves closes the connection.
+        }
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let seen = events.clone();
+    let config = Config { reconnect_delay: 20, ..Config::default() }.auth_secret("s3cr3t".into());
+
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(move |conn| {
+            conn.on_event(move |event| seen.borrow_mut().push(event));
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| conn.handle(stream, |_: &Message| Ok::<_, String>(())))
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+    assert_eq!(&events.borrow()[..3], &[
+        ConnectionEvent::Disconnected(addr),
+        ConnectionEvent::Reconnecting(addr),
+        ConnectionEvent::Reconnected(addr),
+    ]);
+}
+
+#[test]
+fn consumers_retry_nsqd_that_hang_in_the_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        drop(accept_subscriber(listener.try_clone().unwrap()));
+        // Restarting, IDENTIFY is never answered.
+        let hung = read_identify(listener.try_clone().unwrap());
+
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_ack(&mut reader), "FIN 0123456789abcdef\n");
+        drop(hung);
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let seen = events.clone();
+    let config = Config { reconnect_delay: 20, dial_timeout: 100, ..Config::default() };
+
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(move |conn| {
+            conn.on_event(move |event| seen.borrow_mut().push(event));
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| conn.handle(stream, |_: &Message| Ok::<_, String>(())))
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+    assert_eq!(&events.borrow()[..4], &[
+        ConnectionEvent::Disconnected(addr),
+        ConnectionEvent::Reconnecting(addr),
+        ConnectionEvent::Reconnecting(addr),
+        ConnectionEvent::Reconnected(addr),
+    ]);
+}
+
+#[test]
+fn publishes_wait_for_the_connection_up_to_the_queue_size() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        drop(accept_identify(listener.try_clone().unwrap(), b"OK"));
+
+        let (mut reader, mut writer) = accept_identify(listener, b"OK");
+        assert_eq!(read_command(&mut reader), "PUB topic\n");
+        assert_eq!(read_body(&mut reader), b"queued");
+        writer.write_all(&frame(0, b"OK")).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { reconnect_delay: 50, publish_queue_size: 1, ..Config::default() };
+
+    let (disconnected, lost) = oneshot::channel();
+    let disconnected = RefCell::new(Some(disconnected));
+    let published = Producer::connect(&addr, &handle, config)
+        .and_then(move |producer| {
+            producer.on_event(move |event| {
+                if event != ConnectionEvent::Disconnected(addr) {
+                    return;
+                }
+                // Only the first time, the stand-in closes again once done.
+                if let Some(disconnected) = disconnected.borrow_mut().take() {
+                    disconnected.send(()).unwrap();
+                }
+            });
+            lost.map_err(|_| unreachable!()).and_then(move |()| {
+                let queued = producer.publish("topic".into(), "queued");
+                producer.publish("topic".into(), "dropped")
+                    .then(move |res| {
+                        match res {
+                            Err(NsqError::IOError(ref err)) => assert_eq!(err.kind(), ErrorKind::NotConnected),
+                            _ => panic!("published beyond the queue"),
+                        }
+                        // Queued publishes fail along with the last handle.
+                        queued.map(move |resp| {
+                            drop(producer);
+                            resp
+                        })
+                    })
+            })
+        });
+
+    assert_eq!(core.run(published).unwrap(), "OK");
+    server.join().unwrap();
+}
+
+#[test]
+fn producers_retry_nsqd_that_hang_in_the_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        drop(accept_identify(listener.try_clone().unwrap(), b"OK"));
+        // Restarting, IDENTIFY is never answered.
+        let hung = read_identify(listener.try_clone().unwrap());
+
+        let (mut reader, mut writer) = accept_identify(listener, b"OK");
+        assert_eq!(read_command(&mut reader), "PUB topic\n");
+        assert_eq!(read_body(&mut reader), b"queued");
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        drop(hung);
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { reconnect_delay: 20, dial_timeout: 100, publish_queue_size: 1, ..Config::default() };
+
+    let (disconnected, lost) = oneshot::channel();
+    let disconnected = RefCell::new(Some(disconnected));
+    let published = Producer::connect(&addr, &handle, config)
+        .and_then(move |producer| {
+            producer.on_event(move |event| {
+                if event != ConnectionEvent::Disconnected(addr) {
+                    return;
+                }
+                if let Some(disconnected) = disconnected.borrow_mut().take() {
+                    disconnected.send(()).unwrap();
+                }
+            });
+            lost.map_err(|_| unreachable!()).and_then(move |()| {
+                producer.publish("topic".into(), "queued").map(move |resp| {
+                    drop(producer);
+                    resp
+                })
+            })
+        });
+
+    assert_eq!(core.run(published).unwrap(), "OK");
+    server.join().unwrap();
+}
@@ -0,0 +1,142 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::net::TcpListener;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+
+use common::{accept_subscriber, attempted_message, message, read_command};
+
+#[test]
+fn requeue_sends_the_delay_in_milliseconds() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdeg", b"deferred")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0123456789abcdeg 0\n");
+        // Backing off would have sent RDY 0 first.
+        writer.write_all(&message(b"0123456789abcdeh", b"handled")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdeh\n");
+
+        // Only this one backs off, the RDY is sent independently.
+        writer.write_all(&message(b"0123456789abcdef", b"failed")).unwrap();
+        let mut commands = vec![read_command(&mut reader), read_command(&mut reader)];
+        commands.sort();
+        assert_eq!(commands, vec!["RDY 0\n", "REQ 0123456789abcdef 1500\n"]);
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    // The stream ends when the server thread panics.
+    let config = Config { reconnect_delay: 0, ..Config::default() };
+
+    let _ = core.run(
+        Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| {
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| stream.take(3).for_each(move |message| {
+                    match &message.message_body[..] {
+                        b"failed" => drop(conn.requeue(message.message_id, Duration::from_millis(1500))),
+                        b"deferred" => drop(conn.requeue_without_backoff(message.message_id, Duration::from_millis(0))),
+                        _ => drop(conn.fin(message.message_id)),
+                    }
+                    Ok(())
+                }))
+        })
+    );
+
+    core.turn(Some(Duration::from_millis(100)));
+    server.join().unwrap();
+}
+
+#[test]
+fn failed_handlers_requeue_with_a_growing_delay() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&attempted_message(b"0000000000000001", 1, b"fail")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0000000000000001 2000\n");
+        writer.write_all(&attempted_message(b"0000000000000002", 3, b"fail")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0000000000000002 6000\n");
+        writer.write_all(&attempted_message(b"0000000000000003", 9, b"fail")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0000000000000003 10000\n");
+        writer.write_all(&attempted_message(b"0000000000000004", 9, b"ok")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0000000000000004\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    // Without backoff, which would send RDY in between.
+    let config = Config { requeue_delay: 2000, max_requeue_delay: 10_000, max_backoff_duration: 0, ..Config::default() };
+
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| {
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| conn.handle(stream, |message| {
+                    if &message.message_body[..] == b"ok" { Ok(()) } else { Err("failed") }
+                }))
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    // The server thread panics, and drops `done`, on an unexpected command.
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn messages_past_max_attempts_are_given_up_on() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&attempted_message(b"0000000000000001", 3, b"fail")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 0000000000000001 270000\n");
+        writer.write_all(&attempted_message(b"0000000000000002", 4, b"fail")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0000000000000002\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { max_attempts: 3, max_backoff_duration: 0, ..Config::default() };
+    let given_up = Rc::new(RefCell::new(Vec::new()));
+    let handled = Rc::new(RefCell::new(Vec::new()));
+
+    let (given_up_hook, handler_log) = (given_up.clone(), handled.clone());
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(move |conn| {
+            conn.on_give_up(move |message| given_up_hook.borrow_mut().push(message.attempts));
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| conn.handle(stream, move |message| {
+                    handler_log.borrow_mut().push(message.attempts);
+                    Err("failed")
+                }))
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+    assert_eq!(*handled.borrow(), vec![3]);
+    assert_eq!(*given_up.borrow(), vec![4]);
+}
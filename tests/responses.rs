@@ -0,0 +1,91 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+
+use futures::{Future, Stream};
+use futures::future::join_all;
+use tokio_core::reactor::Core;
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::error::NsqError;
+use nsqueue::producer::Producer;
+
+use common::{accept_identify, accept_subscriber, frame, message, read_body, read_command};
+
+#[test]
+fn publish_confirmations_line_up_with_their_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, mut writer) = accept_identify(listener, b"OK");
+        // All three are sent before any is answered.
+        for body in [b"first", b"other", b"third"] {
+            assert_eq!(read_command(&mut reader), "PUB topic\n");
+            assert_eq!(read_body(&mut reader), body);
+        }
+        writer.write_all(&frame(0, b"OK")).unwrap();
+        writer.write_all(&frame(1, b"E_PUB_FAILED PUB failed exiting")).unwrap();
+        writer.write_all(&frame(0, b"OK")).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let published = Producer::connect(&addr, &handle, Config::default())
+        .and_then(|producer| {
+            let publishes: Vec<_> = ["first", "other", "third"].iter()
+                .map(|body| producer.publish("topic".into(), *body).then(Ok::<_, NsqError>))
+                .collect();
+            join_all(publishes)
+        });
+    let results = core.run(published).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(results[0].as_ref().unwrap(), "OK");
+    match results[1] {
+        Err(NsqError::PubFailed(ref text)) => assert_eq!(text, "PUB failed exiting"),
+        _ => panic!("expected E_PUB_FAILED"),
+    }
+    assert_eq!(results[2].as_ref().unwrap(), "OK");
+}
+
+#[test]
+fn errors_about_acknowledgements_arrive_with_the_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"late")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+        writer.write_all(&frame(1, b"E_FIN_FAILED FIN 0123456789abcdef failed ID not in flight")).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { reconnect_delay: 0, ..Config::default() };
+
+    let consumed = Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| {
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| stream.for_each(move |delivery| {
+                    // Written even though the future is never polled.
+                    drop(conn.fin(delivery.message_id));
+                    Ok(())
+                }))
+        });
+
+    match core.run(consumed) {
+        Err(NsqError::FinFailed(ref text)) => assert_eq!(text, "FIN 0123456789abcdef failed ID not in flight"),
+        _ => panic!("expected E_FIN_FAILED"),
+    }
+    server.join().unwrap();
+}
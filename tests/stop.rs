@@ -0,0 +1,120 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Timeout};
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+use nsqueue::response::Message;
+
+use common::{accept_subscriber, frame, message, read_command};
+
+// Handles each message for as many milliseconds as its body says and
+// stops the consumer after `stop_after` milliseconds.
+fn run<F>(config: Config, stop_after: u64, server: F)
+    where F: FnOnce(TcpListener) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || server(listener));
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (handled, ended) = oneshot::channel();
+
+    let stopped = Consumer::connect(&addr, &handle, config)
+        .and_then(move |conn| {
+            conn.subscribe("topic".into(), "channel".into()).map(move |stream| (conn, stream))
+        })
+        .and_then(move |(conn, stream)| {
+            let sleeping = handle.clone();
+            let handling = conn.add_handler(stream, move |message: &Message| {
+                let took = String::from_utf8_lossy(&message.message_body).parse().unwrap();
+                Timeout::new(Duration::from_millis(took), &sleeping).unwrap().map_err(|err| err.to_string())
+            }, 2);
+            // The stream ends once stopped.
+            handle.spawn(handling.then(|res| handled.send(res.is_ok()).map_err(|_| ())));
+
+            Timeout::new(Duration::from_millis(stop_after), &handle).unwrap()
+                .from_err()
+                .and_then(move |()| conn.stop())
+        });
+
+    core.run(stopped).unwrap();
+    server.join().unwrap();
+    assert!(core.run(ended).unwrap(), "handling failed");
+}
+
+#[test]
+fn stopping_waits_for_messages_in_flight() {
+    let config = Config { max_in_flight: 2, ..Config::default() };
+    run(config, 50, |listener| {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"000000000000000a", b"0")).unwrap();
+        writer.write_all(&message(b"000000000000000b", b"200")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 000000000000000a\n");
+
+        assert_eq!(read_command(&mut reader), "RDY 0\n");
+        assert_eq!(read_command(&mut reader), "CLS\n");
+        writer.write_all(&frame(0, b"CLOSE_WAIT")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 000000000000000b\n");
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+}
+
+#[test]
+fn messages_still_in_flight_are_requeued_after_the_drain_timeout() {
+    let config = Config { drain_timeout: 100, ..Config::default() };
+    run(config, 50, |listener| {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"000000000000000a", b"300")).unwrap();
+
+        assert_eq!(read_command(&mut reader), "RDY 0\n");
+        assert_eq!(read_command(&mut reader), "CLS\n");
+        writer.write_all(&frame(0, b"CLOSE_WAIT")).unwrap();
+        assert_eq!(read_command(&mut reader), "REQ 000000000000000a 0\n");
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+}
+
+#[test]
+fn dropping_a_consumer_closes_its_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (closed, on_closed) = oneshot::channel();
+
+    let server = thread::spawn(move || {
+        let (mut reader, _writer) = accept_subscriber(listener);
+        reader.get_ref().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        closed.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { reconnect_delay: 0, ..Config::default() };
+
+    let subscribed = Consumer::connect(&addr, &handle, config)
+        .and_then(|conn| conn.subscribe("topic".into(), "channel".into()));
+    drop(core.run(subscribed).unwrap());
+
+    core.run(on_closed).unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,160 @@
+extern crate futures;
+extern crate openssl;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread::{self, JoinHandle};
+
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use tokio_core::reactor::Core;
+
+use nsqueue::config::{Config, TlsConfig};
+use nsqueue::error::NsqError;
+use nsqueue::producer::Producer;
+
+use common::{accept_identify, frame, read_body, read_command};
+
+const CA: &[u8] = include_bytes!("fixtures/tls/ca.pem");
+const CLIENT_CERT: &[u8] = include_bytes!("fixtures/tls/client.pem");
+const CLIENT_KEY: &[u8] = include_bytes!("fixtures/tls/client.key");
+
+const TLS_AGREED: &[u8] = br#"{"max_rdy_count":2500,"tls_v1":true}"#;
+
+/// What the stand-in saw once the connection was upgraded.
+#[derive(Debug, PartialEq)]
+struct Seen {
+    command: String,
+    client_cn: Option<String>,
+    secret: Option<Vec<u8>>,
+}
+
+/// Serves one client, upgrading to TLS with the named certificate after
+/// IDENTIFY. Returns `None` when the TLS handshake fails.
+fn serve(cert: &'static str, mutual: bool) -> (SocketAddr, JoinHandle<Option<Seen>>) {
+    serve_with(cert, mutual, TLS_AGREED)
+}
+
+fn serve_with(cert: &'static str, mutual: bool, identify: &'static [u8]) -> (SocketAddr, JoinHandle<Option<Seen>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_certificate_chain_file(format!("tests/fixtures/tls/{}.pem", cert)).unwrap();
+    acceptor.set_private_key_file(format!("tests/fixtures/tls/{}.key", cert), SslFiletype::PEM).unwrap();
+    if mutual {
+        acceptor.set_ca_file("tests/fixtures/tls/ca.pem").unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    let acceptor = acceptor.build();
+
+    let server = thread::spawn(move || {
+        let (reader, _writer) = accept_identify(listener, identify);
+        let stream = acceptor.accept(reader.into_inner()).ok()?;
+
+        let client_cn = stream.ssl().peer_certificate().map(|cert| {
+            let entry = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap();
+            entry.data().to_string().unwrap()
+        });
+
+        let mut stream = BufReader::new(stream);
+        stream.get_mut().write_all(&frame(0, b"OK")).unwrap();
+        let mut command = read_command(&mut stream);
+        let mut secret = None;
+        if command == "AUTH\n" {
+            secret = Some(read_body(&mut stream));
+            stream.get_mut().write_all(&frame(0, br#"{"identity":"tls","permission_count":1}"#)).unwrap();
+            command = read_command(&mut stream);
+        }
+        read_body(&mut stream);
+        stream.get_mut().write_all(&frame(0, b"OK")).unwrap();
+
+        Some(Seen { command, client_cn, secret })
+    });
+
+    (addr, server)
+}
+
+fn publish(addr: &SocketAddr, config: Config) -> Result<String, NsqError> {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let conn = core.run(Producer::connect(addr, &handle, config))?;
+    core.run(conn.publish("topic".into(), "over tls"))
+}
+
+fn trusting_ca() -> TlsConfig {
+    TlsConfig { root_certificates: vec![CA.to_vec()], ..TlsConfig::default() }
+}
+
+#[test]
+fn publishes_over_tls_with_custom_root() {
+    let (addr, server) = serve("server", false);
+
+    assert_eq!(publish(&addr, Config::default().tls(trusting_ca())).unwrap(), "OK");
+    let seen = server.join().unwrap().unwrap();
+    assert_eq!(seen, Seen { command: "PUB topic\n".into(), client_cn: None, secret: None });
+}
+
+#[test]
+fn client_certificate_is_presented() {
+    let (addr, server) = serve("server", true);
+    let tls = TlsConfig {
+        client_certificate: Some((CLIENT_CERT.to_vec(), CLIENT_KEY.to_vec())),
+        ..trusting_ca()
+    };
+
+    assert_eq!(publish(&addr, Config::default().tls(tls)).unwrap(), "OK");
+    let seen = server.join().unwrap().unwrap();
+    assert_eq!(seen.client_cn.unwrap(), "nsqueue test client");
+}
+
+#[test]
+fn server_name_is_verified() {
+    let (addr, server) = serve("server", false);
+    let tls = TlsConfig { server_name: Some("nsqd.test".into()), ..trusting_ca() };
+    assert!(publish(&addr, Config::default().tls(tls)).is_ok());
+    server.join().unwrap().unwrap();
+
+    let (addr, server) = serve("server", false);
+    let tls = TlsConfig { server_name: Some("elsewhere.test".into()), ..trusting_ca() };
+    assert!(publish(&addr, Config::default().tls(tls)).is_err());
+    assert!(server.join().unwrap().is_none());
+}
+
+#[test]
+fn untrusted_certificates_need_insecure_mode() {
+    let (addr, server) = serve("untrusted", false);
+    assert!(publish(&addr, Config::default().tls(trusting_ca())).is_err());
+    assert!(server.join().unwrap().is_none());
+
+    let (addr, server) = serve("untrusted", false);
+    let tls = TlsConfig { insecure_skip_verify: true, ..TlsConfig::default() };
+    assert_eq!(publish(&addr, Config::default().tls(tls)).unwrap(), "OK");
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn refused_tls_is_not_downgraded() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        accept_identify(listener, br#"{"max_rdy_count":2500,"tls_v1":false}"#);
+    });
+
+    assert!(publish(&addr, Config::default().tls(trusting_ca())).is_err());
+    server.join().unwrap();
+}
+
+#[test]
+fn auth_is_sent_over_tls() {
+    let (addr, server) = serve_with("server", false, br#"{"max_rdy_count":2500,"tls_v1":true,"auth_required":true}"#);
+    let config = Config::default().tls(trusting_ca()).auth_secret("s3cr3t".into());
+
+    assert_eq!(publish(&addr, config).unwrap(), "OK");
+    let seen = server.join().unwrap().unwrap();
+    assert_eq!(seen.secret.unwrap(), b"s3cr3t");
+}
@@ -0,0 +1,93 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate nsqueue;
+
+mod common;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Timeout};
+
+use nsqueue::config::Config;
+use nsqueue::consumer::Consumer;
+
+use common::{accept_subscriber, accept_subscriber_with, message, read_command};
+
+#[test]
+fn touch_sends_touch() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let (mut reader, mut writer) = accept_subscriber(listener);
+        writer.write_all(&message(b"0123456789abcdef", b"payload")).unwrap();
+        assert_eq!(read_command(&mut reader), "TOUCH 0123456789abcdef\n");
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let consumer = Consumer::connect(&addr, &handle, Config::default())
+        .and_then(|conn| {
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| {
+                    let toucher = conn.clone();
+                    conn.handle(stream, move |message| {
+                        drop(toucher.touch(message.message_id));
+                        Ok::<_, String>(())
+                    })
+                })
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+}
+
+#[test]
+fn long_handlers_are_touched_up_to_max_msg_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = oneshot::channel();
+
+    thread::spawn(move || {
+        let reply = br#"{"msg_timeout":400,"max_msg_timeout":500}"#;
+        let (mut reader, mut writer) = accept_subscriber_with(listener, reply);
+        writer.write_all(&message(b"0123456789abcdef", b"slow")).unwrap();
+        // At 200ms and 400ms, a touch at 600ms would be past max_msg_timeout.
+        assert_eq!(read_command(&mut reader), "TOUCH 0123456789abcdef\n");
+        assert_eq!(read_command(&mut reader), "TOUCH 0123456789abcdef\n");
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdef\n");
+
+        // Quick handlers are not touched.
+        writer.write_all(&message(b"0123456789abcdeg", b"quick")).unwrap();
+        assert_eq!(read_command(&mut reader), "FIN 0123456789abcdeg\n");
+        done.send(()).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let config = Config { auto_touch: true, ..Config::default() };
+
+    let handler_handle = handle.clone();
+    let consumer = Consumer::connect(&addr, &handle, config)
+        .and_then(move |conn| {
+            conn.subscribe("topic".into(), "channel".into())
+                .and_then(move |stream| conn.handle(stream, move |message| {
+                    let took = if &message.message_body[..] == b"slow" { 900 } else { 0 };
+                    Timeout::new(Duration::from_millis(took), &handler_handle).unwrap()
+                }))
+        })
+        .map_err(|err| panic!("{}", err));
+    handle.spawn(consumer);
+
+    core.run(finished).expect("server failed");
+}